@@ -0,0 +1,76 @@
+// Searches that must return the same results are put in a single form, so that they
+// share a cache entry and are treated alike when checking if they are allowed. Only
+// the parts that are case insensitive or unordered in every schema are changed. The
+// values in filters are left as they are, as their matching rules aren't known.
+
+use crate::dn::canonical_dn;
+use ldap3_proto::proto::{LdapFilter, LdapSearchRequest};
+
+/// The filter with its attribute names in lower case, and the terms of its ands and
+/// ors sorted with duplicates removed.
+pub fn canonical_filter(filter: &LdapFilter) -> LdapFilter {
+    match filter {
+        LdapFilter::And(filters) => LdapFilter::And(canonical_terms(filters)),
+        LdapFilter::Or(filters) => LdapFilter::Or(canonical_terms(filters)),
+        LdapFilter::Not(filter) => LdapFilter::Not(Box::new(canonical_filter(filter))),
+        LdapFilter::Equality(attr, value) => {
+            LdapFilter::Equality(attr.to_lowercase(), value.clone())
+        }
+        LdapFilter::Substring(attr, substring) => {
+            LdapFilter::Substring(attr.to_lowercase(), substring.clone())
+        }
+        LdapFilter::GreaterOrEqual(attr, value) => {
+            LdapFilter::GreaterOrEqual(attr.to_lowercase(), value.clone())
+        }
+        LdapFilter::LessOrEqual(attr, value) => {
+            LdapFilter::LessOrEqual(attr.to_lowercase(), value.clone())
+        }
+        LdapFilter::Present(attr) => LdapFilter::Present(attr.to_lowercase()),
+        LdapFilter::Approx(attr, value) => LdapFilter::Approx(attr.to_lowercase(), value.clone()),
+        LdapFilter::Extensible(assertion) => {
+            let mut assertion = assertion.clone();
+            assertion.type_ = assertion.type_.map(|attr| attr.to_lowercase());
+            LdapFilter::Extensible(assertion)
+        }
+    }
+}
+
+/// The attribute a term is on. Terms are sorted by their attribute first, so that a
+/// template with `(uid=*)` sorts in the same place as the filters it matches.
+fn term_attr(filter: &LdapFilter) -> Option<&str> {
+    match filter {
+        LdapFilter::And(_) | LdapFilter::Or(_) | LdapFilter::Not(_) => None,
+        LdapFilter::Equality(attr, _)
+        | LdapFilter::Substring(attr, _)
+        | LdapFilter::GreaterOrEqual(attr, _)
+        | LdapFilter::LessOrEqual(attr, _)
+        | LdapFilter::Present(attr)
+        | LdapFilter::Approx(attr, _) => Some(attr),
+        LdapFilter::Extensible(assertion) => assertion.type_.as_deref(),
+    }
+}
+
+fn canonical_terms(filters: &[LdapFilter]) -> Vec<LdapFilter> {
+    let mut filters: Vec<LdapFilter> = filters.iter().map(canonical_filter).collect();
+    filters.sort_by(|a, b| (term_attr(a), a).cmp(&(term_attr(b), b)));
+    filters.dedup();
+    filters
+}
+
+/// The requested attributes in lower case, sorted with duplicates removed.
+pub fn canonical_attrs(attrs: &[String]) -> Vec<String> {
+    let mut attrs: Vec<String> = attrs.iter().map(|attr| attr.to_lowercase()).collect();
+    attrs.sort_unstable();
+    attrs.dedup();
+    attrs
+}
+
+/// The search with its base, filter and attributes in their canonical form.
+pub fn canonical_search(sr: LdapSearchRequest) -> LdapSearchRequest {
+    LdapSearchRequest {
+        base: canonical_dn(&sr.base),
+        filter: canonical_filter(&sr.filter),
+        attrs: canonical_attrs(&sr.attrs),
+        ..sr
+    }
+}
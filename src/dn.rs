@@ -0,0 +1,217 @@
+// Distinguished names are compared by their relative distinguished names (RDNs), so
+// that differences in the case of attribute types, spacing, escaping and the order of
+// multi-valued RDNs do not matter. Values keep their case, as their matching rules
+// aren't known.
+
+use regex::Regex;
+use serde_with::DeserializeFromStr;
+use std::str::FromStr;
+
+/// The positions of the separators in a dn that are not escaped or quoted.
+fn separators(dn: &str, sep: char) -> impl Iterator<Item = usize> + '_ {
+    let mut escaped = false;
+    let mut quoted = false;
+    dn.char_indices().filter_map(move |(i, c)| {
+        if escaped {
+            escaped = false;
+            return None;
+        }
+        match c {
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == sep && !quoted => return Some(i),
+            _ => {}
+        }
+        None
+    })
+}
+
+fn split(dn: &str, sep: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    for i in separators(dn, sep) {
+        parts.push(&dn[start..i]);
+        start = i + sep.len_utf8();
+    }
+    parts.push(&dn[start..]);
+    parts
+}
+
+fn is_hex(c: char) -> bool {
+    c.is_ascii_hexdigit()
+}
+
+/// Remove the escaping and quoting from an attribute value.
+fn unescape(value: &str) -> String {
+    let value = value.trim();
+    let value = value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value);
+
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+
+        let mut hex = chars.clone();
+        match (hex.next(), hex.next()) {
+            (Some(hi), Some(lo)) if is_hex(hi) && is_hex(lo) => {
+                let digits = [hi, lo].iter().collect::<String>();
+                if let Ok(byte) = u8::from_str_radix(&digits, 16) {
+                    bytes.push(byte);
+                }
+                chars = hex;
+            }
+            _ => {
+                if let Some(c) = chars.next() {
+                    let mut buf = [0; 4];
+                    bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                }
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Escape an attribute value, so that every value has one representation.
+fn escape(value: &str) -> String {
+    let len = value.chars().count();
+    let mut escaped = String::with_capacity(value.len());
+    for (i, c) in value.chars().enumerate() {
+        let edge = i == 0 || i + 1 == len;
+        match c {
+            '\\' | ',' | '+' | '"' | '<' | '>' | ';' | '=' => escaped.push('\\'),
+            ' ' if edge => escaped.push('\\'),
+            '#' if i == 0 => escaped.push('\\'),
+            _ => {}
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn normalise_rdn(rdn: &str) -> String {
+    let mut avas: Vec<String> = split(rdn, '+')
+        .into_iter()
+        .map(|ava| match ava.split_once('=') {
+            Some((atype, value)) => format!(
+                "{}={}",
+                atype.trim().to_lowercase(),
+                escape(&unescape(value))
+            ),
+            None => ava.trim().to_lowercase(),
+        })
+        .collect();
+    avas.sort_unstable();
+    avas.join("+")
+}
+
+/// The normalised RDNs of a dn, from the leaf to the root. The empty dn has none.
+pub fn rdns(dn: &str) -> Vec<String> {
+    if dn.trim().is_empty() {
+        return Vec::with_capacity(0);
+    }
+    split(dn, ',').into_iter().map(normalise_rdn).collect()
+}
+
+/// The dn in a single form, so that dns that are the same are equal.
+pub fn canonical_dn(dn: &str) -> String {
+    rdns(dn).join(",")
+}
+
+/// Check if two dns are the same.
+pub fn dn_eq(a: &str, b: &str) -> bool {
+    rdns(a) == rdns(b)
+}
+
+/// How many RDNs the dn is below the base, if it is the base or below it. The empty
+/// base contains every dn.
+pub fn dn_depth_below(dn: &str, base: &str) -> Option<usize> {
+    let dn = rdns(dn);
+    let base = rdns(base);
+    dn.ends_with(&base).then(|| dn.len() - base.len())
+}
+
+/// Check if a dn is the base dn, or is below it. The empty base contains every dn.
+pub fn dn_in_subtree(dn: &str, base: &str) -> bool {
+    dn_depth_below(dn, base).is_some()
+}
+
+/// The parent of a dn, or the empty dn if it has no parent.
+pub fn dn_parent(dn: &str) -> &str {
+    separators(dn, ',')
+        .next()
+        .map(|i| dn[i + 1..].trim_start())
+        .unwrap_or("")
+}
+
+/// A pattern that matches dns. A pattern is either:
+///
+/// * `regex:<regex>`, a regular expression that must match the whole canonical dn,
+///   which has its attribute types in lower case and no spaces around separators
+/// * `*,<dn>`, any dn directly below the dn
+/// * `**,<dn>`, any dn below the dn, at any depth
+/// * a dn, which matches only that dn
+///
+/// `*` and `**` alone are any dn other than the empty dn.
+#[derive(DeserializeFromStr, Debug, Clone)]
+pub enum DnPattern {
+    Exact(Vec<String>),
+    Child(Vec<String>),
+    Descendant(Vec<String>),
+    Regex(Regex),
+}
+
+impl FromStr for DnPattern {
+    type Err = regex::Error;
+
+    fn from_str(pattern: &str) -> Result<Self, Self::Err> {
+        if let Some(regex) = pattern.strip_prefix("regex:") {
+            // A pattern is an authorization boundary, so it must match the whole dn and
+            // not only a part of it.
+            return Regex::new(&format!("^(?:{})$", regex)).map(DnPattern::Regex);
+        }
+
+        let pattern = pattern.trim_start();
+        Ok(match pattern.split_once(',') {
+            Some(("**", base)) => DnPattern::Descendant(rdns(base)),
+            Some(("*", base)) => DnPattern::Child(rdns(base)),
+            _ if pattern == "*" || pattern == "**" => DnPattern::Descendant(Vec::with_capacity(0)),
+            _ => DnPattern::Exact(rdns(pattern)),
+        })
+    }
+}
+
+impl DnPattern {
+    pub fn is_exact(&self) -> bool {
+        matches!(self, DnPattern::Exact(_))
+    }
+
+    pub fn matches(&self, dn: &str) -> bool {
+        let dn = rdns(dn);
+        match self {
+            DnPattern::Exact(exact) => &dn == exact,
+            DnPattern::Child(base) => dn.len() == base.len() + 1 && dn.ends_with(base),
+            DnPattern::Descendant(base) => dn.len() > base.len() && dn.ends_with(base),
+            DnPattern::Regex(regex) => regex.is_match(&dn.join(",")),
+        }
+    }
+
+    /// How specific the pattern is, so that the most specific pattern that matches a dn
+    /// can be chosen. The pattern with the most RDNs in its base is the most specific,
+    /// and regular expressions are the least specific.
+    pub fn specificity(&self) -> (bool, usize, bool) {
+        match self {
+            DnPattern::Exact(exact) => (true, exact.len(), true),
+            DnPattern::Child(base) => (true, base.len(), true),
+            DnPattern::Descendant(base) => (true, base.len(), false),
+            DnPattern::Regex(_) => (false, 0, false),
+        }
+    }
+}
@@ -1,60 +1,758 @@
-use concread::arcache::ARCache;
+use concread::arcache::{ARCache, ARCacheBuilder};
 use hashbrown::HashSet;
 use ldap3_proto::parse_ldap_filter_str;
+use ldap3_proto::proto::{
+    LdapDerefAliases, LdapOp, LdapResultCode, LdapSearchRequest, LdapSearchResultEntry,
+};
 use ldap3_proto::{LdapFilter, LdapSearchScope};
-use rustls::pki_types::ServerName;
 use serde::Deserialize;
 use serde_with::DeserializeFromStr;
 use std::collections::BTreeMap;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
 use std::str::FromStr;
-use std::time::Duration;
-use tokio_rustls::TlsConnector;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use url::Url;
 
+pub mod canonical;
+pub mod dn;
+pub mod net;
+pub mod pool;
+pub mod prewarm;
 pub mod proxy;
+pub mod snapshot;
+pub mod stats;
+pub mod tls;
+pub mod upstream;
+pub mod write_log;
 
+use crate::canonical::canonical_filter;
+use crate::dn::{canonical_dn, dn_depth_below, dn_eq, dn_in_subtree, rdns, DnPattern};
+use crate::net::IpNetwork;
+use crate::pool::BindPool;
 use crate::proxy::{CachedValue, SearchCacheKey};
+use crate::stats::CacheStats;
+use crate::upstream::UpstreamSet;
+use crate::write_log::WriteLog;
 
 const MEGABYTES: usize = 1048576;
 
+/// The number of dns whose group policy is cached.
+const GROUP_POLICY_CACHE_SIZE: usize = 4096;
+
 pub const LDAP_CLIENT_CONN_TIMEOUT: Duration = Duration::from_secs(30);
 pub const LDAP_CLIENT_IO_TIMEOUT: Duration = Duration::from_secs(300);
 
 pub struct AppState {
-    pub tls_connector: TlsConnector,
-    pub tls_hostname: ServerName<'static>,
-    pub addrs: Vec<SocketAddr>,
+    pub upstreams: Arc<UpstreamSet>,
+    pub bind_pools: BTreeMap<(String, String), Arc<BindPool>>,
     // Cache later here.
-    pub binddn_map: BTreeMap<String, DnConfig>,
-    pub cache: ARCache<SearchCacheKey, CachedValue>,
+    pub binddn_map: BindMap,
+    pub group_policy: Option<GroupPolicy>,
+    pub cache: Arc<ARCache<SearchCacheKey, CachedValue>>,
     pub cache_entry_timeout: Duration,
+    pub cache_negative_timeout: Duration,
+    pub cache_max_entry_bytes: usize,
+    pub cache_compares: bool,
+    pub cache_result_codes: Vec<LdapResultCode>,
+    pub cache_stale_while_revalidate: Duration,
+    pub cache_stale_if_error: Duration,
+    /// The expired results that are being searched for again.
+    pub cache_refreshing: Arc<Mutex<HashSet<SearchCacheKey>>>,
+    pub cache_stats: Arc<CacheStats>,
+    /// The writes that could have changed cached results.
+    pub write_log: Arc<WriteLog>,
     pub max_incoming_ber_size: Option<usize>,
     pub max_proxy_ber_size: Option<usize>,
     pub allow_all_bind_dns: bool,
     pub remote_ip_addr_info: AddrInfoSource,
+    pub starttls_required: bool,
+    pub denial_response: DenialResponse,
+    pub bind_denial_response: BindDenialResponse,
+    pub denial_message: Option<String>,
+}
+
+/// The bind maps, resolved by dn. A dn's own bind map is used first, and then the
+/// bind map with the most specific pattern that matches the dn.
+#[derive(Debug, Default)]
+pub struct BindMap {
+    exact: BTreeMap<Vec<String>, DnConfig>,
+    // Ordered from the most specific pattern to the least.
+    patterns: Vec<(DnPattern, DnConfig)>,
+}
+
+impl BindMap {
+    pub fn new(binddn_map: &BTreeMap<String, DnConfig>) -> Result<Self, (String, regex::Error)> {
+        let mut exact = BTreeMap::new();
+        let mut patterns = Vec::new();
+
+        for (key, dnconfig) in binddn_map {
+            match DnPattern::from_str(key).map_err(|err| (key.clone(), err))? {
+                DnPattern::Exact(rdns) => {
+                    exact.insert(rdns, dnconfig.clone());
+                }
+                pattern => patterns.push((pattern, dnconfig.clone())),
+            }
+        }
+
+        // This is stable, so patterns that are as specific are in the order of their keys.
+        patterns.sort_by_key(|(pattern, _)| std::cmp::Reverse(pattern.specificity()));
+
+        Ok(BindMap { exact, patterns })
+    }
+
+    pub fn get(&self, dn: &str) -> Option<&DnConfig> {
+        self.exact.get(&rdns(dn)).or_else(|| {
+            self.patterns
+                .iter()
+                .find(|(pattern, _)| pattern.matches(dn))
+                .map(|(_, dnconfig)| dnconfig)
+        })
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &DnConfig> {
+        self.exact
+            .values()
+            .chain(self.patterns.iter().map(|(_, dnconfig)| dnconfig))
+    }
+}
+
+/// How the policy of a dn without a bind map is chosen from the groups it is a member
+/// of, once it has bound.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GroupPolicyConfig {
+    /// The attribute of the bound entry that lists its groups.
+    #[serde(default = "default_group_policy_attribute")]
+    pub attribute: String,
+    /// A search for the groups of the bound dn, which is used instead of the attribute.
+    pub search: Option<GroupSearch>,
+    /// Seconds that the policy chosen for a dn is cached.
+    #[serde(default = "default_group_policy_cache_timeout")]
+    pub cache_timeout: u64,
+    /// The groups and the names of the policies they select. The first group that the
+    /// dn is a member of is used.
+    pub groups: Vec<(DnPattern, String)>,
+}
+
+/// A subtree search for groups. `${dn}` in an equality value of the filter is replaced
+/// by the bound dn.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GroupSearch {
+    pub base: String,
+    pub filter: LdapFilterWrapper,
+}
+
+fn substitute_dn(filter: &LdapFilter, dn: &str) -> LdapFilter {
+    match filter {
+        LdapFilter::And(filters) => LdapFilter::And(
+            filters
+                .iter()
+                .map(|filter| substitute_dn(filter, dn))
+                .collect(),
+        ),
+        LdapFilter::Or(filters) => LdapFilter::Or(
+            filters
+                .iter()
+                .map(|filter| substitute_dn(filter, dn))
+                .collect(),
+        ),
+        LdapFilter::Not(filter) => LdapFilter::Not(Box::new(substitute_dn(filter, dn))),
+        LdapFilter::Equality(attr, value) if value == "${dn}" => {
+            LdapFilter::Equality(attr.clone(), dn.to_string())
+        }
+        filter => filter.clone(),
+    }
+}
+
+#[derive(Debug, Clone)]
+struct CachedGroupPolicy {
+    valid_until: Instant,
+    policy: Option<String>,
+}
+
+/// The policies that are chosen by group membership, and the policy chosen for each
+/// dn that has recently bound.
+pub struct GroupPolicy {
+    config: GroupPolicyConfig,
+    policies: BTreeMap<String, DnConfig>,
+    cache: ARCache<Vec<String>, CachedGroupPolicy>,
+    cache_timeout: Duration,
+}
+
+impl GroupPolicy {
+    pub fn new(
+        config: &GroupPolicyConfig,
+        policies: &BTreeMap<String, DnConfig>,
+    ) -> Result<Self, String> {
+        if let Some((_, name)) = config
+            .groups
+            .iter()
+            .find(|(_, name)| !policies.contains_key(name))
+        {
+            return Err(format!("The policy {} does not exist", name));
+        }
+
+        // A policy is chosen once the dn has bound, when it's too late to bind as another.
+        if let Some(name) = policies
+            .iter()
+            .find(|(_, policy)| policy.map_to_dn.is_some() || policy.map_to_secret.is_some())
+            .map(|(name, _)| name)
+        {
+            return Err(format!(
+                "The policy {} can't have a map_to_dn or map_to_secret",
+                name
+            ));
+        }
+
+        let cache = ARCacheBuilder::new()
+            .set_size(GROUP_POLICY_CACHE_SIZE, 0)
+            .build()
+            .ok_or_else(|| "Unable to build group policy cache".to_string())?;
+
+        Ok(GroupPolicy {
+            config: config.clone(),
+            policies: policies.clone(),
+            cache,
+            cache_timeout: Duration::from_secs(config.cache_timeout),
+        })
+    }
+
+    /// The search that finds the groups of a dn.
+    pub fn search_request(&self, dn: &str) -> LdapSearchRequest {
+        let (base, scope, filter, attrs) = match self.config.search.as_ref() {
+            Some(search) => (
+                search.base.clone(),
+                LdapSearchScope::Subtree,
+                substitute_dn(&search.filter.inner, dn),
+                vec!["1.1".to_string()],
+            ),
+            None => (
+                dn.to_string(),
+                LdapSearchScope::Base,
+                LdapFilter::Present("objectClass".to_string()),
+                vec![self.config.attribute.clone()],
+            ),
+        };
+
+        LdapSearchRequest {
+            base,
+            scope,
+            aliases: LdapDerefAliases::Never,
+            sizelimit: 0,
+            timelimit: 0,
+            typesonly: false,
+            filter,
+            attrs,
+        }
+    }
+
+    /// The groups in an entry returned by the search of `search_request`.
+    pub fn entry_groups(&self, entry: &LdapSearchResultEntry) -> Vec<String> {
+        if self.config.search.is_some() {
+            return vec![entry.dn.clone()];
+        }
+
+        entry
+            .attributes
+            .iter()
+            .filter(|attr| attr.atype.eq_ignore_ascii_case(&self.config.attribute))
+            .flat_map(|attr| attr.vals.iter())
+            .map(|val| String::from_utf8_lossy(val).into_owned())
+            .collect()
+    }
+
+    /// The name of the policy that was chosen for the dn, if it is still cached. The
+    /// dn may have had no policy.
+    pub fn cached(&self, dn: &str) -> Option<Option<String>> {
+        let now = Instant::now();
+        let mut cache_read_txn = self.cache.read();
+        cache_read_txn
+            .get(&rdns(dn))
+            .filter(|cache_value| cache_value.valid_until > now)
+            .map(|cache_value| cache_value.policy.clone())
+    }
+
+    /// The name of the policy selected by the first configured group that is in the
+    /// groups.
+    pub fn select(&self, groups: &[String]) -> Option<String> {
+        self.config
+            .groups
+            .iter()
+            .find(|(pattern, _)| groups.iter().any(|group| pattern.matches(group)))
+            .map(|(_, name)| name.clone())
+    }
+
+    pub fn cache_policy(&self, dn: &str, policy: Option<String>) {
+        let cache_value = CachedGroupPolicy {
+            valid_until: Instant::now() + self.cache_timeout,
+            policy,
+        };
+        let mut cache_read_txn = self.cache.read();
+        cache_read_txn.insert(rdns(dn), cache_value);
+        drop(cache_read_txn);
+        self.cache.try_quiesce();
+    }
+
+    pub fn policy(&self, name: &str) -> Option<&DnConfig> {
+        self.policies.get(name)
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Default)]
 pub struct DnConfig {
     pub map_to_dn: Option<String>,
     pub map_to_secret: Option<String>,
+    /// If not empty, the dn may only bind from addresses in these networks.
+    #[serde(default)]
+    pub allowed_networks: Vec<IpNetwork>,
+    #[serde(default)]
+    pub allowed_queries: HashSet<AllowedQuery>,
+    #[serde(default)]
+    pub allowed_compares: Vec<(DnPattern, String)>,
+    pub write_policy: Option<WritePolicy>,
+    /// If not empty, only these attributes may be requested or returned.
+    #[serde(default)]
+    pub allowed_attributes: HashSet<String>,
+    /// These attributes may never be requested or returned.
+    #[serde(default)]
+    pub denied_attributes: HashSet<String>,
+    /// A filter that is combined with the filter of every search, so that only the
+    /// entries that match both are returned.
+    pub forced_filter: Option<LdapFilterWrapper>,
+    /// The most entries that a search may return, and the most seconds it may take.
+    /// Searches that ask for no limit, or a greater one, are given these limits.
+    pub max_size_limit: Option<i32>,
+    pub max_time_limit: Option<i32>,
+    /// The dns in a cache group share cached results. Otherwise results are shared by
+    /// the dns that are mapped to the same dn.
+    pub cache_group: Option<String>,
+    /// How operations of this dn that aren't allowed are answered, instead of the
+    /// global setting.
+    pub denial_response: Option<DenialResponse>,
+    pub denial_message: Option<String>,
+}
+
+/// How an operation that isn't allowed is answered. Operations other than searches
+/// can't succeed with an empty result, so they are answered with
+/// `insufficientAccessRights` instead.
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DenialResponse {
+    #[default]
+    EmptySuccess,
+    InsufficientAccessRights,
+    NoSuchObject,
+    UnwillingToPerform,
+}
+
+/// How a bind of a dn that isn't allowed is answered.
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BindDenialResponse {
+    #[default]
+    OperationsError,
+    InvalidCredentials,
+    InsufficientAccessRights,
+    UnwillingToPerform,
+}
+
+/// A search that is allowed. This is either a base, scope and filter, or a subtree
+/// where searches may be rooted at any base within it.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum AllowedQuery {
+    Exact(
+        #[serde(deserialize_with = "deserialize_canonical_dn")] String,
+        LdapSearchScope,
+        LdapFilterWrapper,
+    ),
+    Subtree(SubtreeQuery),
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq, Hash)]
+#[serde(deny_unknown_fields)]
+pub struct SubtreeQuery {
+    pub subtree: String,
+    pub filter: LdapFilterWrapper,
+    /// The scopes allowed at each depth below the subtree, starting with the subtree
+    /// itself. The last applies to all greater depths. If empty, any scope is allowed.
+    #[serde(default)]
+    pub scopes: Vec<Vec<LdapSearchScope>>,
+}
+
+/// A search that is kept in the cache. It is made as a client that binds as the dn
+/// and searches with no limits, no controls, and without dereferencing aliases.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PrewarmQuery {
+    /// The dn that the search is made as. The empty dn is anonymous.
+    #[serde(default)]
+    pub bind_dn: String,
+    /// The secret of the dn, which isn't needed if the dn has a map_to_secret.
+    pub bind_secret: Option<String>,
+    pub base: String,
+    pub scope: LdapSearchScope,
+    pub filter: LdapFilterWrapper,
+    #[serde(default)]
+    pub attrs: Vec<String>,
+}
+
+impl PrewarmQuery {
+    pub fn search_request(&self) -> LdapSearchRequest {
+        LdapSearchRequest {
+            base: self.base.clone(),
+            scope: self.scope.clone(),
+            aliases: LdapDerefAliases::Never,
+            sizelimit: 0,
+            timelimit: 0,
+            typesonly: false,
+            filter: self.filter.inner.clone(),
+            attrs: self.attrs.clone(),
+        }
+    }
+}
+
+fn deserialize_canonical_dn<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    String::deserialize(deserializer).map(|dn| canonical_dn(&dn))
+}
+
+impl AllowedQuery {
+    fn allows(&self, base: &str, scope: &LdapSearchScope, filter: &LdapFilter) -> bool {
+        match self {
+            AllowedQuery::Exact(allowed_base, allowed_scope, allowed_filter) => {
+                allowed_scope == scope
+                    && dn_eq(allowed_base, base)
+                    && allowed_filter.matches(filter)
+            }
+            AllowedQuery::Subtree(subtree_query) => {
+                let Some(depth) = dn_depth_below(base, &subtree_query.subtree) else {
+                    return false;
+                };
+                let scope_allowed = subtree_query.scopes.is_empty()
+                    || subtree_query
+                        .scopes
+                        .get(depth)
+                        .or(subtree_query.scopes.last())
+                        .is_some_and(|scopes| scopes.contains(scope));
+                scope_allowed && subtree_query.filter.matches(filter)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum WriteOperation {
+    Add,
+    Modify,
+    Delete,
+    ModifyDn,
+}
+
+/// The writes that a dn may make. Writes are only allowed to entries within the
+/// subtree, and may only set the listed attributes.
+#[derive(Debug, Clone, Deserialize)]
+pub struct WritePolicy {
+    pub operations: HashSet<WriteOperation>,
+    pub subtree: String,
     #[serde(default)]
-    pub allowed_queries: HashSet<(String, LdapSearchScope, LdapFilterWrapper)>,
+    pub attributes: HashSet<String>,
 }
 
+impl WritePolicy {
+    fn attribute_allowed(&self, atype: &str) -> bool {
+        self.attributes
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(atype))
+    }
+
+    /// Check if this write request is allowed. An add may only contain the listed
+    /// attributes, and a modify may only change them. A rename must remain within
+    /// the subtree.
+    pub fn allows(&self, op: &LdapOp) -> bool {
+        let (operation, dn) = match op {
+            LdapOp::AddRequest(req) => (WriteOperation::Add, &req.dn),
+            LdapOp::ModifyRequest(req) => (WriteOperation::Modify, &req.dn),
+            LdapOp::DelRequest(dn) => (WriteOperation::Delete, dn),
+            LdapOp::ModifyDNRequest(req) => (WriteOperation::ModifyDn, &req.dn),
+            _ => return false,
+        };
+
+        if !self.operations.contains(&operation) || !dn_in_subtree(dn, &self.subtree) {
+            return false;
+        }
+
+        match op {
+            LdapOp::AddRequest(req) => req
+                .attributes
+                .iter()
+                .all(|attr| self.attribute_allowed(&attr.atype)),
+            LdapOp::ModifyRequest(req) => req
+                .changes
+                .iter()
+                .all(|change| self.attribute_allowed(&change.modification.atype)),
+            // The subtree itself can't be renamed, as it would no longer be the subtree.
+            LdapOp::ModifyDNRequest(req) => {
+                !dn_eq(&req.dn, &self.subtree)
+                    && req
+                        .new_superior
+                        .as_ref()
+                        .is_none_or(|new_superior| dn_in_subtree(new_superior, &self.subtree))
+            }
+            _ => true,
+        }
+    }
+}
+
+impl DnConfig {
+    /// Check if the dn may bind from this client address.
+    pub fn address_allowed(&self, addr: IpAddr) -> bool {
+        self.allowed_networks.is_empty()
+            || self
+                .allowed_networks
+                .iter()
+                .any(|network| network.contains(addr))
+    }
+
+    /// If this dn has allowed queries, it may only make those searches.
+    pub fn queries_restricted(&self) -> bool {
+        !self.allowed_queries.is_empty()
+    }
+
+    /// If this dn has allowed compares, it may only make those compares.
+    pub fn compares_restricted(&self) -> bool {
+        !self.allowed_compares.is_empty()
+    }
+
+    /// Check if a search is allowed, either as one of the allowed queries, or by
+    /// matching an allowed query as a template or subtree. The base and filter must be
+    /// canonical, as the allowed queries are.
+    pub fn query_allowed(&self, base: &str, scope: &LdapSearchScope, filter: &LdapFilter) -> bool {
+        if !self.queries_restricted() {
+            return true;
+        }
+
+        let allow_key = AllowedQuery::Exact(
+            base.to_string(),
+            scope.clone(),
+            LdapFilterWrapper {
+                inner: filter.clone(),
+            },
+        );
+
+        self.allowed_queries.contains(&allow_key)
+            || self
+                .allowed_queries
+                .iter()
+                .any(|allowed_query| allowed_query.allows(base, scope, filter))
+    }
+
+    /// Check if this attribute may be requested or returned. Any options of the
+    /// attribute, such as `;binary`, are ignored.
+    pub fn attribute_allowed(&self, atype: &str) -> bool {
+        let atype = atype.split(';').next().unwrap_or(atype);
+        let contains = |attributes: &HashSet<String>| {
+            attributes
+                .iter()
+                .any(|attribute| attribute.eq_ignore_ascii_case(atype))
+        };
+
+        !contains(&self.denied_attributes)
+            && (self.allowed_attributes.is_empty() || contains(&self.allowed_attributes))
+    }
+
+    /// Check if a search filter only uses attributes that are allowed, as otherwise
+    /// whether entries match could reveal the values of attributes that aren't.
+    pub fn filter_allowed(&self, filter: &LdapFilter) -> bool {
+        match filter {
+            LdapFilter::And(filters) | LdapFilter::Or(filters) => {
+                filters.iter().all(|filter| self.filter_allowed(filter))
+            }
+            LdapFilter::Not(filter) => self.filter_allowed(filter),
+            LdapFilter::Equality(atype, _)
+            | LdapFilter::Substring(atype, _)
+            | LdapFilter::GreaterOrEqual(atype, _)
+            | LdapFilter::LessOrEqual(atype, _)
+            | LdapFilter::Present(atype)
+            | LdapFilter::Approx(atype, _) => self.attribute_allowed(atype),
+            LdapFilter::Extensible(assertion) => match assertion.type_.as_deref() {
+                Some(atype) => self.attribute_allowed(atype),
+                // Without a type every attribute of the entries is matched.
+                None => self.allowed_attributes.is_empty() && self.denied_attributes.is_empty(),
+            },
+        }
+    }
+
+    /// The attributes to request from the ldap server for these requested attributes,
+    /// without any that aren't allowed.
+    pub fn requested_attrs(&self, attrs: &[String]) -> Vec<String> {
+        // Special selectors are kept, as the entries that are returned are filtered.
+        let is_selector = |attr: &str| matches!(attr, "*" | "+" | "1.1");
+
+        // All user attributes become the allowed attributes.
+        let all_user_attrs = attrs.is_empty() || attrs.iter().any(|attr| attr == "*");
+        if all_user_attrs && !self.allowed_attributes.is_empty() {
+            let mut requested: Vec<String> =
+                attrs.iter().filter(|attr| *attr == "+").cloned().collect();
+            requested.extend(
+                self.allowed_attributes
+                    .iter()
+                    .filter(|attr| self.attribute_allowed(attr))
+                    .cloned(),
+            );
+            requested.sort_unstable();
+            return requested;
+        }
+
+        let requested: Vec<String> = attrs
+            .iter()
+            .filter(|attr| is_selector(attr) || self.attribute_allowed(attr))
+            .cloned()
+            .collect();
+
+        // An empty list requests all attributes, so if all the requested attributes were
+        // removed, request none instead.
+        if requested.is_empty() && !attrs.is_empty() {
+            vec!["1.1".to_string()]
+        } else {
+            requested
+        }
+    }
+
+    /// The filter to send to the ldap server for this requested filter.
+    pub fn search_filter(&self, filter: LdapFilter) -> LdapFilter {
+        match self.forced_filter.as_ref() {
+            Some(forced_filter) => LdapFilter::And(vec![forced_filter.inner.clone(), filter]),
+            None => filter,
+        }
+    }
+
+    /// The size limit to send to the ldap server for this requested size limit.
+    pub fn size_limit(&self, sizelimit: i32) -> i32 {
+        clamp_limit(sizelimit, self.max_size_limit)
+    }
+
+    /// The time limit to send to the ldap server for this requested time limit.
+    pub fn time_limit(&self, timelimit: i32) -> i32 {
+        clamp_limit(timelimit, self.max_time_limit)
+    }
+
+    /// Remove the attributes from an entry that aren't allowed.
+    pub fn filter_entry(&self, entry: &mut LdapSearchResultEntry) {
+        entry
+            .attributes
+            .retain(|attr| self.attribute_allowed(&attr.atype));
+    }
+
+    /// Check if this write is allowed. Writes are only allowed by a write policy.
+    pub fn write_allowed(&self, op: &LdapOp) -> bool {
+        self.write_policy
+            .as_ref()
+            .is_some_and(|write_policy| write_policy.allows(op))
+    }
+
+    /// Check if a compare of this attribute on this dn is allowed.
+    pub fn compare_allowed(&self, dn: &str, atype: &str) -> bool {
+        if !self.attribute_allowed(atype) {
+            return false;
+        }
+
+        // A compare isn't limited by the allowed queries or the forced filter, so it could
+        // reveal entries that they hide. Only the allowed compares are allowed then.
+        if !self.compares_restricted() {
+            return !self.queries_restricted() && self.forced_filter.is_none();
+        }
+
+        self.allowed_compares
+            .iter()
+            .any(|(allowed_dn, allowed_atype)| {
+                allowed_dn.matches(dn) && allowed_atype.eq_ignore_ascii_case(atype)
+            })
+    }
+}
+
+/// A limit of 0 is no limit.
+fn clamp_limit(limit: i32, max: Option<i32>) -> i32 {
+    match max {
+        Some(max) if max > 0 && (limit <= 0 || limit > max) => max,
+        _ => limit,
+    }
+}
+
+/// A filter from the configuration, which is parsed into its canonical form.
 #[derive(DeserializeFromStr, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct LdapFilterWrapper {
     pub inner: LdapFilter,
 }
 
+impl LdapFilterWrapper {
+    /// Check if a search filter matches this filter as a template. In a template a
+    /// presence filter such as `(uid=*)` also matches an equality filter with any
+    /// value, and an equality value may be a typed placeholder:
+    ///
+    /// * `${any}` matches any value
+    /// * `${number}` matches a decimal number
+    /// * `${name}` matches a value of letters, digits, and `-`, `_`, `.` or `@`
+    ///
+    /// The filters must otherwise have the same structure, with their terms in the same
+    /// order. Filters under a not are not templates, and must be the same.
+    pub fn matches(&self, filter: &LdapFilter) -> bool {
+        filter_matches(&self.inner, filter)
+    }
+}
+
+fn placeholder_matches(template: &str, value: &str) -> bool {
+    match template {
+        "${any}" => true,
+        "${number}" => !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()),
+        "${name}" => {
+            !value.is_empty()
+                && value
+                    .chars()
+                    .all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.' | '@'))
+        }
+        _ => template == value,
+    }
+}
+
+fn filter_matches(template: &LdapFilter, filter: &LdapFilter) -> bool {
+    match (template, filter) {
+        (LdapFilter::And(templates), LdapFilter::And(filters))
+        | (LdapFilter::Or(templates), LdapFilter::Or(filters)) => {
+            templates.len() == filters.len()
+                && templates
+                    .iter()
+                    .zip(filters.iter())
+                    .all(|(template, filter)| filter_matches(template, filter))
+        }
+        // A template under a not would exclude fewer entries than the template
+        // allows, so the filter must be the same.
+        (LdapFilter::Not(template), LdapFilter::Not(filter)) => template == filter,
+        (
+            LdapFilter::Present(t_attr),
+            LdapFilter::Present(attr) | LdapFilter::Equality(attr, _),
+        ) => t_attr.eq_ignore_ascii_case(attr),
+        (LdapFilter::Equality(t_attr, t_value), LdapFilter::Equality(attr, value)) => {
+            t_attr.eq_ignore_ascii_case(attr) && placeholder_matches(t_value, value)
+        }
+        (template, filter) => template == filter,
+    }
+}
+
 impl FromStr for LdapFilterWrapper {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         parse_ldap_filter_str(s)
-            .map(|inner| LdapFilterWrapper { inner })
+            .map(|inner| LdapFilterWrapper {
+                inner: canonical_filter(&inner),
+            })
             .map_err(|err| err.to_string())
     }
 }
@@ -67,6 +765,64 @@ fn default_cache_entry_timeout() -> u64 {
     1800
 }
 
+fn default_cache_negative_timeout() -> u64 {
+    60
+}
+
+fn default_cache_result_codes() -> Vec<LdapResultCode> {
+    vec![
+        LdapResultCode::Success,
+        LdapResultCode::SizeLimitExceeded,
+        LdapResultCode::CompareFalse,
+        LdapResultCode::CompareTrue,
+        LdapResultCode::NoSuchObject,
+    ]
+}
+
+fn default_cache_refresh_ahead() -> u64 {
+    60
+}
+
+fn default_cache_max_entry_bytes() -> usize {
+    8 * MEGABYTES
+}
+
+fn default_group_policy_attribute() -> String {
+    "memberOf".to_string()
+}
+
+fn default_group_policy_cache_timeout() -> u64 {
+    300
+}
+
+fn default_starttls_required() -> bool {
+    true
+}
+
+fn default_ldap_server_weight() -> u32 {
+    1
+}
+
+fn default_ldap_health_check_interval() -> u64 {
+    30
+}
+
+fn default_ldap_failure_threshold() -> u32 {
+    1
+}
+
+fn default_ldap_retry_after() -> u64 {
+    30
+}
+
+fn default_ldap_connect_timeout() -> u64 {
+    30
+}
+
+fn default_ldap_pool_idle_timeout() -> u64 {
+    300
+}
+
 #[derive(Debug, Deserialize, Default, Clone, Copy)]
 pub enum AddrInfoSource {
     #[default]
@@ -74,19 +830,83 @@ pub enum AddrInfoSource {
     ProxyV2,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+pub struct LdapServerConfig {
+    pub url: Url,
+    #[serde(default)]
+    pub priority: u32,
+    #[serde(default = "default_ldap_server_weight")]
+    pub weight: u32,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Config {
     pub bind: SocketAddr,
     pub tls_key: PathBuf,
     pub tls_chain: PathBuf,
+    pub tls_reload_interval: Option<u64>,
+
+    pub starttls_bind: Option<SocketAddr>,
+    #[serde(default = "default_starttls_required")]
+    pub starttls_required: bool,
 
     #[serde(default = "default_cache_bytes")]
     pub cache_bytes: usize,
     #[serde(default = "default_cache_entry_timeout")]
     pub cache_entry_timeout: u64,
+    #[serde(default = "default_cache_negative_timeout")]
+    pub cache_negative_timeout: u64,
+    #[serde(default = "default_cache_max_entry_bytes")]
+    pub cache_max_entry_bytes: usize,
+    #[serde(default)]
+    pub cache_compares: bool,
+    #[serde(default = "default_cache_result_codes")]
+    pub cache_result_codes: Vec<LdapResultCode>,
+    /// Where the cache is saved on shutdown, to be loaded on start up.
+    pub cache_snapshot_path: Option<PathBuf>,
+    /// Seconds between saves of the cache while running, if it should be saved then.
+    pub cache_snapshot_interval: Option<u64>,
+    /// Searches that are made on start up, and again before their results expire, so
+    /// that clients making them find them cached.
+    #[serde(default)]
+    pub cache_prewarm: Vec<PrewarmQuery>,
+    /// Seconds before a prewarmed result expires that it is searched for again, or at
+    /// most half the time it is cached for.
+    #[serde(default = "default_cache_refresh_ahead")]
+    pub cache_refresh_ahead: u64,
+    /// Seconds after a result expires that it is still sent to clients, while it is
+    /// searched for again in the background.
+    #[serde(default)]
+    pub cache_stale_while_revalidate: u64,
+    /// Seconds after a result expires that it is still sent to clients if the ldap server
+    /// can't be searched. While it can't be reached, dns that are mapped with a secret may
+    /// still bind to be sent cached results.
+    #[serde(default)]
+    pub cache_stale_if_error: u64,
+    /// Seconds between logs of the cache statistics. 0 disables them.
+    #[serde(default)]
+    pub cache_stats_interval: u64,
 
     pub ldap_ca: PathBuf,
-    pub ldap_url: Url,
+    pub ldap_url: Option<Url>,
+    #[serde(default)]
+    pub ldap_servers: Vec<LdapServerConfig>,
+    #[serde(default)]
+    pub ldap_insecure_plaintext: bool,
+    #[serde(default = "default_ldap_health_check_interval")]
+    pub ldap_health_check_interval: u64,
+    #[serde(default = "default_ldap_failure_threshold")]
+    pub ldap_failure_threshold: u32,
+    #[serde(default = "default_ldap_retry_after")]
+    pub ldap_retry_after: u64,
+    #[serde(default = "default_ldap_connect_timeout")]
+    pub ldap_connect_timeout: u64,
+    #[serde(default)]
+    pub ldap_pool_min_idle: usize,
+    #[serde(default)]
+    pub ldap_pool_max_idle: usize,
+    #[serde(default = "default_ldap_pool_idle_timeout")]
+    pub ldap_pool_idle_timeout: u64,
 
     #[serde(default)]
     pub remote_ip_addr_info: AddrInfoSource,
@@ -97,6 +917,16 @@ pub struct Config {
     #[serde(default)]
     pub allow_all_bind_dns: bool,
 
+    #[serde(default)]
+    pub denial_response: DenialResponse,
+    #[serde(default)]
+    pub bind_denial_response: BindDenialResponse,
+    pub denial_message: Option<String>,
+
+    pub group_policy: Option<GroupPolicyConfig>,
+    #[serde(default)]
+    pub policies: BTreeMap<String, DnConfig>,
+
     #[serde(flatten)]
     pub binddn_map: BTreeMap<String, DnConfig>,
 }
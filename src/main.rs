@@ -13,20 +13,31 @@
 use clap::Parser;
 use concread::arcache::ARCacheBuilder;
 use ldap3_proto::LdapCodec;
-use ldap_proxy::{proxy, AddrInfoSource, AppState, Config, LDAP_CLIENT_CONN_TIMEOUT};
+use ldap_proxy::pool::{pool_maintenance_task, BindPool};
+use ldap_proxy::prewarm::prewarm_task;
+use ldap_proxy::snapshot::{cache_snapshot_task, load_cache, save_cache};
+use ldap_proxy::stats::cache_stats_task;
+use ldap_proxy::tls::ReloadableCertResolver;
+use ldap_proxy::upstream::{build_upstream, health_check_task, UpstreamServer, UpstreamSet};
+use ldap_proxy::write_log::WriteLog;
+use ldap_proxy::{
+    proxy, AddrInfoSource, AppState, BindMap, Config, GroupPolicy, LdapServerConfig,
+    LDAP_CLIENT_CONN_TIMEOUT, LDAP_CLIENT_IO_TIMEOUT,
+};
 use rustls::{
-    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+    pki_types::{pem::PemObject, CertificateDer},
     ClientConfig, ServerConfig,
 };
+use std::collections::BTreeMap;
 use std::fs::File;
-use std::io::Read;
+use std::io::{ErrorKind, Read};
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::net::TcpListener;
 use tokio::net::TcpStream;
-use tokio::sync::broadcast;
+use tokio::sync::{broadcast, watch};
 use tokio::time::timeout;
 use tokio_rustls::{TlsAcceptor, TlsConnector};
 use tokio_util::codec::{FramedRead, FramedWrite};
@@ -43,16 +54,12 @@ struct Opt {
     config: PathBuf,
 }
 
-async fn ldaps_tls_acceptor(
+async fn remote_addr_info(
     tcpstream: TcpStream,
-    client_socket_addr: SocketAddr,
-    tls_acceptor: TlsAcceptor,
-    app_state: Arc<AppState>,
-) {
+    app_state: &AppState,
+) -> Option<(TcpStream, Option<SocketAddr>)> {
     use haproxy_protocol::{ProxyHdrV2, RemoteAddress};
 
-    let max_incoming_ber_size = app_state.max_incoming_ber_size;
-
     let (tcpstream, reported_socket_addr) = match app_state.remote_ip_addr_info {
         AddrInfoSource::None => (tcpstream, None),
         AddrInfoSource::ProxyV2 => match timeout(
@@ -65,13 +72,13 @@ async fn ldaps_tls_acceptor(
                 let remote_socket_addr = match hdr.to_remote_addr() {
                     RemoteAddress::Local => {
                         debug!("haproxy check");
-                        return;
+                        return None;
                     }
                     RemoteAddress::TcpV4 { src, dst: _ } => SocketAddr::from(src),
                     RemoteAddress::TcpV6 { src, dst: _ } => SocketAddr::from(src),
                     remote_addr => {
                         error!(?remote_addr, "remote address in proxy header is invalid");
-                        return;
+                        return None;
                     }
                 };
 
@@ -79,17 +86,33 @@ async fn ldaps_tls_acceptor(
             }
             Ok(Err(err)) => {
                 error!(?err, "Unable to process proxy v2 header");
-                return;
+                return None;
             }
             Err(_) => {
                 error!("proxy v2 header timeout");
-                return;
+                return None;
             }
         },
     };
 
     debug!(remote_addr_source = ?app_state.remote_ip_addr_info, ?reported_socket_addr);
 
+    Some((tcpstream, reported_socket_addr))
+}
+
+async fn ldaps_tls_acceptor(
+    tcpstream: TcpStream,
+    client_socket_addr: SocketAddr,
+    tls_acceptor: TlsAcceptor,
+    app_state: Arc<AppState>,
+) {
+    let max_incoming_ber_size = app_state.max_incoming_ber_size;
+
+    let Some((tcpstream, reported_socket_addr)) = remote_addr_info(tcpstream, &app_state).await
+    else {
+        return;
+    };
+
     let tlsstream = match timeout(LDAP_CLIENT_CONN_TIMEOUT, tls_acceptor.accept(tcpstream)).await {
         Ok(Ok(ta)) => ta,
         Ok(Err(err)) => {
@@ -115,11 +138,32 @@ async fn ldaps_tls_acceptor(
     ));
 }
 
+async fn ldap_starttls_acceptor(
+    tcpstream: TcpStream,
+    client_socket_addr: SocketAddr,
+    tls_acceptor: TlsAcceptor,
+    app_state: Arc<AppState>,
+) {
+    let Some((tcpstream, reported_socket_addr)) = remote_addr_info(tcpstream, &app_state).await
+    else {
+        return;
+    };
+
+    proxy::starttls_client_process(
+        tcpstream,
+        client_socket_addr,
+        reported_socket_addr,
+        tls_acceptor,
+        app_state,
+    )
+    .await;
+}
+
 async fn ldaps_acceptor(
     listener: TcpListener,
     tls_parms: TlsAcceptor,
     mut broadcast_rx: broadcast::Receiver<bool>,
-    app_state: Arc<AppState>,
+    app_state_rx: watch::Receiver<Arc<AppState>>,
 ) {
     loop {
         tokio::select! {
@@ -129,7 +173,7 @@ async fn ldaps_acceptor(
             accept_result = listener.accept() => {
                 match accept_result {
                     Ok((tcpstream, client_socket_addr)) => {
-                        let c_app_state = app_state.clone();
+                        let c_app_state = app_state_rx.borrow().clone();
                         tokio::spawn(ldaps_tls_acceptor( tcpstream, client_socket_addr, tls_parms.clone(), c_app_state ));
                     }
                     Err(e) => {
@@ -142,18 +186,43 @@ async fn ldaps_acceptor(
     debug!("Stopped ldaps acceptor");
 }
 
-async fn setup(opt: &Opt) {
-    info!("Starting ldap-proxy");
+async fn ldap_acceptor(
+    listener: TcpListener,
+    tls_parms: TlsAcceptor,
+    mut broadcast_rx: broadcast::Receiver<bool>,
+    app_state_rx: watch::Receiver<Arc<AppState>>,
+) {
+    loop {
+        tokio::select! {
+            _ = broadcast_rx.recv() => {
+                break;
+            }
+            accept_result = listener.accept() => {
+                match accept_result {
+                    Ok((tcpstream, client_socket_addr)) => {
+                        let c_app_state = app_state_rx.borrow().clone();
+                        tokio::spawn(ldap_starttls_acceptor( tcpstream, client_socket_addr, tls_parms.clone(), c_app_state ));
+                    }
+                    Err(e) => {
+                        error!("LDAP StartTLS acceptor error, continuing -> {:?}", e);
+                    }
+                }
+            }
+        }
+    }
+    debug!("Stopped ldap acceptor");
+}
 
-    let mut f = match File::open(&opt.config) {
+fn load_config(path: &Path) -> Result<Config, ()> {
+    let mut f = match File::open(path) {
         Ok(f) => f,
         Err(e) => {
             error!(
                 "Unable to open config file '{}' [{:?}] 🥺",
-                &opt.config.display(),
+                path.display(),
                 e
             );
-            return;
+            return Err(());
         }
     };
 
@@ -161,74 +230,62 @@ async fn setup(opt: &Opt) {
     if let Err(e) = f.read_to_string(&mut contents) {
         error!(
             "unable to read config contents from '{}' {:?}",
-            &opt.config.display(),
+            path.display(),
             e
         );
-        return;
+        return Err(());
     };
 
-    let sync_config: Config = match toml::from_str(contents.as_str()) {
-        Ok(c) => c,
+    match toml::from_str(contents.as_str()) {
+        Ok(c) => Ok(c),
         Err(e) => {
-            eprintln!(
-                "unable to parse config from '{}' {:?}",
-                &opt.config.display(),
-                e
-            );
-            return;
+            eprintln!("unable to parse config from '{}' {:?}", path.display(), e);
+            Err(())
         }
-    };
-
-    debug!(?sync_config);
-
-    // Do we need to re-process the config to a different shape?
-
-    // Setup the broadcast system.
-    let (broadcast_tx, broadcast_rx) = broadcast::channel(1);
-
-    // Let the listening port ready.
-    let listener = match TcpListener::bind(&sync_config.bind).await {
-        Ok(l) => l,
-        Err(e) => {
-            error!(
-                "Could not bind to LDAP server address {} -> {:?}",
-                sync_config.bind, e
-            );
-            return;
-        }
-    };
+    }
+}
 
+/// Build the app state of a configuration. On reload the running configuration and app
+/// state are given, and the cache and its state are kept, so that it isn't emptied and
+/// the writes made by sessions that are still open invalidate it.
+fn build_app_state(
+    sync_config: &Config,
+    running: Option<(&Config, &AppState)>,
+) -> Result<AppState, ()> {
     // Setup the data for the client handles.
 
-    let url = sync_config.ldap_url;
-
-    match url.scheme() {
-        "ldaps" => {}
-        _ => {
-            error!("Unable to proceed. LDAPS is required in remote ldap_url");
-            return;
-        }
-    };
-
-    let hostname = match url.host_str() {
-        Some(s) => s,
-        None => {
-            error!("Unable to determine hostname from url");
-            return;
-        }
-    };
+    // The single ldap_url is treated as a server with the default priority and weight.
+    let ldap_servers: Vec<LdapServerConfig> = sync_config
+        .ldap_url
+        .iter()
+        .map(|url| LdapServerConfig {
+            url: url.clone(),
+            priority: 0,
+            weight: 1,
+        })
+        .chain(sync_config.ldap_servers.iter().cloned())
+        .collect();
+
+    if ldap_servers.is_empty() {
+        error!("Unable to proceed. At least one of ldap_url or ldap_servers is required");
+        return Err(());
+    }
 
-    let addrs = match url.socket_addrs(|| Some(636)) {
-        Ok(a) => a,
-        Err(e) => {
-            error!(?e, "url address resolver error");
-            return;
+    let mut servers = Vec::with_capacity(ldap_servers.len());
+    for ldap_server in ldap_servers {
+        if ldap_server.weight == 0 {
+            error!(url = %ldap_server.url, "ldap server weight must be greater than 0");
+            return Err(());
         }
-    };
 
-    if addrs.is_empty() {
-        error!("url address resolved to no addresses");
-        return;
+        let upstream = build_upstream(&ldap_server.url, sync_config.ldap_insecure_plaintext)
+            .map_err(|_| ())?;
+        servers.push(UpstreamServer::new(
+            ldap_server.url.to_string(),
+            upstream,
+            ldap_server.priority,
+            ldap_server.weight,
+        ));
     }
 
     let mut root_cert_store = rustls::RootCertStore::empty();
@@ -237,13 +294,13 @@ async fn setup(opt: &Opt) {
         Ok(c) => c,
         Err(err) => {
             error!(?err, "Invalid CA PEM File");
-            return;
+            return Err(());
         }
     };
 
     if let Err(err) = root_cert_store.add(ca_cert) {
         error!(?err, "Failed to add CA to certificate store");
-        return;
+        return Err(());
     };
 
     let tls_config = ClientConfig::builder()
@@ -252,88 +309,362 @@ async fn setup(opt: &Opt) {
 
     let tls_connector = TlsConnector::from(Arc::new(tls_config));
 
-    let tls_hostname = match ServerName::try_from(hostname.to_string()) {
-        Ok(h) => h,
-        Err(err) => {
-            error!(?err, "Invalid LDAP Server Hostname");
-            return;
-        }
-    };
+    let upstreams = Arc::new(UpstreamSet::new(
+        servers,
+        tls_connector,
+        sync_config.max_proxy_ber_size,
+        sync_config.ldap_failure_threshold,
+        Duration::from_secs(sync_config.ldap_retry_after),
+        Duration::from_secs(sync_config.ldap_connect_timeout),
+    ));
 
-    let Some(cache) = ARCacheBuilder::new()
-        .set_size(sync_config.cache_bytes, 0)
-        .build()
-    else {
-        error!("Unable to build query cache");
-        return;
+    // The cache can't be resized, so it is only replaced when its size changes.
+    let cache = match running {
+        Some((running_config, running_app_state))
+            if running_config.cache_bytes == sync_config.cache_bytes =>
+        {
+            running_app_state.cache.clone()
+        }
+        _ => {
+            let Some(cache) = ARCacheBuilder::new()
+                .set_size(sync_config.cache_bytes, 0)
+                .build()
+            else {
+                error!("Unable to build query cache");
+                return Err(());
+            };
+            Arc::new(cache)
+        }
     };
 
     let cache_entry_timeout = Duration::from_secs(sync_config.cache_entry_timeout);
+    let cache_negative_timeout = Duration::from_secs(sync_config.cache_negative_timeout);
+    let cache_max_entry_bytes = sync_config.cache_max_entry_bytes;
+    let cache_compares = sync_config.cache_compares;
+    let cache_result_codes = sync_config.cache_result_codes.clone();
+    let cache_stale_while_revalidate =
+        Duration::from_secs(sync_config.cache_stale_while_revalidate);
+    let cache_stale_if_error = Duration::from_secs(sync_config.cache_stale_if_error);
+    // Writes are kept for as long as a result searched for before them may be used.
+    let write_log_retention = cache_entry_timeout.max(cache_negative_timeout)
+        + cache_stale_while_revalidate.max(cache_stale_if_error)
+        + LDAP_CLIENT_IO_TIMEOUT;
+    let (cache_refreshing, cache_stats, write_log) = match running {
+        Some((_, running_app_state)) => {
+            running_app_state
+                .write_log
+                .extend_retention(write_log_retention);
+            (
+                running_app_state.cache_refreshing.clone(),
+                running_app_state.cache_stats.clone(),
+                running_app_state.write_log.clone(),
+            )
+        }
+        None => (
+            Arc::default(),
+            Arc::default(),
+            Arc::new(WriteLog::new(write_log_retention)),
+        ),
+    };
 
     let max_incoming_ber_size = sync_config.max_incoming_ber_size;
     let max_proxy_ber_size = sync_config.max_proxy_ber_size;
     let allow_all_bind_dns = sync_config.allow_all_bind_dns;
     let remote_ip_addr_info = sync_config.remote_ip_addr_info;
+    let starttls_required = sync_config.starttls_required;
+
+    let binddn_map = BindMap::new(&sync_config.binddn_map).map_err(|(key, err)| {
+        error!(?err, "Invalid bind map pattern {}", key);
+    })?;
+    let group_policy = sync_config
+        .group_policy
+        .as_ref()
+        .map(|group_policy| GroupPolicy::new(group_policy, &sync_config.policies))
+        .transpose()
+        .map_err(|err| {
+            error!(?err, "Invalid group policy");
+        })?;
+    let denial_response = sync_config.denial_response;
+    let bind_denial_response = sync_config.bind_denial_response;
+    let denial_message = sync_config.denial_message.clone();
+
+    // Connections for mapped identities with a secret can be shared, so each distinct
+    // identity has a pool.
+    let mut bind_pools = BTreeMap::new();
+    if sync_config.ldap_pool_max_idle > 0 {
+        for dnconfig in binddn_map.values() {
+            if let (Some(map_to_dn), Some(map_to_secret)) =
+                (dnconfig.map_to_dn.as_ref(), dnconfig.map_to_secret.as_ref())
+            {
+                bind_pools
+                    .entry((map_to_dn.clone(), map_to_secret.clone()))
+                    .or_insert_with(|| {
+                        Arc::new(BindPool::new(
+                            map_to_dn.clone(),
+                            map_to_secret.clone(),
+                            upstreams.clone(),
+                            sync_config.ldap_pool_min_idle,
+                            sync_config.ldap_pool_max_idle,
+                            Duration::from_secs(sync_config.ldap_pool_idle_timeout),
+                        ))
+                    });
+            }
+        }
+    }
 
-    let app_state = Arc::new(AppState {
-        tls_connector,
-        tls_hostname,
-        addrs,
-        binddn_map: sync_config.binddn_map.clone(),
+    Ok(AppState {
+        upstreams,
+        bind_pools,
+        binddn_map,
+        group_policy,
         cache,
         cache_entry_timeout,
+        cache_negative_timeout,
+        cache_max_entry_bytes,
+        cache_compares,
+        cache_result_codes,
+        cache_stale_while_revalidate,
+        cache_stale_if_error,
+        cache_refreshing,
+        cache_stats,
+        write_log,
         max_incoming_ber_size,
         max_proxy_ber_size,
         allow_all_bind_dns,
         remote_ip_addr_info,
-    });
+        starttls_required,
+        denial_response,
+        bind_denial_response,
+        denial_message,
+    })
+}
 
-    // Setup the TLS server parameters
-    let tls_chain_iter = match CertificateDer::pem_file_iter(&sync_config.tls_chain) {
-        Ok(tci) => tci,
-        Err(err) => {
-            error!(?err, "Failed to access tls certificate chain");
-            return;
-        }
+fn start_background_tasks(app_state: &Arc<AppState>, sync_config: &Config) {
+    if sync_config.ldap_health_check_interval == 0 {
+        debug!("ldap server health checks are disabled");
+    } else {
+        tokio::spawn(health_check_task(
+            Arc::downgrade(&app_state.upstreams),
+            Duration::from_secs(sync_config.ldap_health_check_interval),
+        ));
+    }
+
+    // Check pools often enough that idle connections are closed promptly.
+    let pool_interval = Duration::from_secs((sync_config.ldap_pool_idle_timeout / 2).max(1));
+    for bind_pool in app_state.bind_pools.values() {
+        tokio::spawn(pool_maintenance_task(
+            Arc::downgrade(bind_pool),
+            pool_interval,
+        ));
+    }
+
+    let refresh_ahead = Duration::from_secs(sync_config.cache_refresh_ahead);
+    for query in sync_config.cache_prewarm.iter() {
+        tokio::spawn(prewarm_task(
+            Arc::downgrade(app_state),
+            query.clone(),
+            refresh_ahead,
+        ));
+    }
+}
+
+/// Re-read and re-validate the configuration and tls certificate, and if they are valid
+/// swap them in for new client sessions. Sessions that are already open continue with the state they
+/// started with. If the new configuration is invalid, the current state is retained.
+///
+/// The configuration that was applied is returned, so that the next reload is compared
+/// with it. The settings that require a restart keep their running values.
+fn reload_app_state(
+    config_path: &Path,
+    running_config: &Config,
+    app_state_tx: &watch::Sender<Arc<AppState>>,
+    cert_resolver: &ReloadableCertResolver,
+) -> Option<Config> {
+    info!("Reloading configuration from '{}'", config_path.display());
+
+    let Ok(mut sync_config) = load_config(config_path) else {
+        error!("Configuration reload failed, continuing with the current configuration");
+        return None;
     };
 
-    let tls_chain = match tls_chain_iter.collect::<Result<Vec<_>, _>>() {
-        Ok(tci) => tci,
-        Err(err) => {
-            error!(?err, "Failed to parse tls certificate chain");
-            return;
-        }
+    debug!(?sync_config);
+
+    if sync_config.bind != running_config.bind {
+        warn!(
+            "Changing the bind address from {} to {} requires a restart, ignoring",
+            running_config.bind, sync_config.bind
+        );
+    }
+
+    if sync_config.starttls_bind != running_config.starttls_bind {
+        warn!(
+            "Changing the starttls bind address from {:?} to {:?} requires a restart, ignoring",
+            running_config.starttls_bind, sync_config.starttls_bind
+        );
+    }
+
+    let running_app_state = app_state_tx.borrow().clone();
+    let Ok(app_state) = build_app_state(&sync_config, Some((running_config, &running_app_state)))
+    else {
+        error!("Configuration reload failed, continuing with the current configuration");
+        return None;
     };
 
-    let tls_key = match PrivateKeyDer::from_pem_file(&sync_config.tls_key) {
-        Ok(pk) => pk,
-        Err(err) => {
-            error!(?err, "Error reading server private key");
+    // The certificate is swapped only if it's valid, so a failure here leaves
+    // everything as it was.
+    if let Err(err) = cert_resolver.reload(&sync_config.tls_chain, &sync_config.tls_key) {
+        error!(
+            ?err,
+            "Configuration reload failed, continuing with the current configuration"
+        );
+        return None;
+    }
+
+    let app_state = Arc::new(app_state);
+    start_background_tasks(&app_state, &sync_config);
+    app_state_tx.send_replace(app_state);
+
+    // These are only read on start up, so the running values remain in use.
+    sync_config.bind = running_config.bind;
+    sync_config.starttls_bind = running_config.starttls_bind;
+    sync_config.tls_reload_interval = running_config.tls_reload_interval;
+    sync_config.cache_snapshot_path = running_config.cache_snapshot_path.clone();
+    sync_config.cache_snapshot_interval = running_config.cache_snapshot_interval;
+    sync_config.cache_stats_interval = running_config.cache_stats_interval;
+
+    info!("Configuration reloaded");
+    Some(sync_config)
+}
+
+async fn setup(opt: &Opt) {
+    info!("Starting ldap-proxy");
+
+    let Ok(mut sync_config) = load_config(&opt.config) else {
+        return;
+    };
+
+    debug!(?sync_config);
+
+    // Setup the broadcast system.
+    let (broadcast_tx, broadcast_rx) = broadcast::channel(1);
+
+    // Let the listening port ready.
+    let listener = match TcpListener::bind(&sync_config.bind).await {
+        Ok(l) => l,
+        Err(e) => {
+            error!(
+                "Could not bind to LDAP server address {} -> {:?}",
+                sync_config.bind, e
+            );
             return;
         }
     };
 
-    let tls_config = match ServerConfig::builder()
-        .with_no_client_auth()
-        .with_single_cert(tls_chain, tls_key)
-    {
-        Ok(tc) => tc,
+    let starttls_listener = match sync_config.starttls_bind {
+        Some(starttls_bind) => match TcpListener::bind(&starttls_bind).await {
+            Ok(l) => Some(l),
+            Err(e) => {
+                error!(
+                    "Could not bind to LDAP StartTLS server address {} -> {:?}",
+                    starttls_bind, e
+                );
+                return;
+            }
+        },
+        None => None,
+    };
+
+    let Ok(app_state) = build_app_state(&sync_config, None) else {
+        return;
+    };
+
+    // Results cached before the last shutdown are loaded, so that they aren't all
+    // searched for again.
+    if let Some(cache_snapshot_path) = sync_config.cache_snapshot_path.as_ref() {
+        match load_cache(&app_state.cache, cache_snapshot_path) {
+            Ok(count) => info!("Loaded {} cached results", count),
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                debug!("No cache snapshot to load");
+            }
+            Err(err) => warn!(?err, "Unable to load the cache snapshot, starting empty"),
+        }
+    }
+
+    // New connections take the current app state from here, allowing it to be
+    // replaced on reload without disturbing existing sessions.
+    let app_state = Arc::new(app_state);
+    start_background_tasks(&app_state, &sync_config);
+    let (app_state_tx, app_state_rx) = watch::channel(app_state);
+
+    if let (Some(cache_snapshot_path), Some(cache_snapshot_interval)) = (
+        sync_config.cache_snapshot_path.clone(),
+        sync_config.cache_snapshot_interval.filter(|secs| *secs > 0),
+    ) {
+        tokio::spawn(cache_snapshot_task(
+            app_state_rx.clone(),
+            cache_snapshot_path,
+            Duration::from_secs(cache_snapshot_interval),
+        ));
+    }
+
+    if sync_config.cache_stats_interval > 0 {
+        tokio::spawn(cache_stats_task(
+            app_state_rx.clone(),
+            Duration::from_secs(sync_config.cache_stats_interval),
+        ));
+    }
+
+    // Setup the TLS server parameters. The certificate is provided by a resolver
+    // so that it can be replaced when it is renewed.
+    let tls_config_builder = ServerConfig::builder().with_no_client_auth();
+
+    let cert_resolver = match ReloadableCertResolver::new(
+        &sync_config.tls_chain,
+        &sync_config.tls_key,
+        tls_config_builder.crypto_provider().clone(),
+    ) {
+        Ok(cr) => Arc::new(cr),
         Err(err) => {
             error!(?err, "Failed to build TLS Server Configuration");
             return;
         }
     };
 
+    let tls_config = tls_config_builder.with_cert_resolver(cert_resolver.clone());
+
     let tls_acceptor = TlsAcceptor::from(Arc::new(tls_config));
 
-    // Setup the acceptor.
+    // Setup the acceptors.
+    let starttls_acceptor = starttls_listener.map(|starttls_listener| {
+        let tls_acceptor = tls_acceptor.clone();
+        let broadcast_rx = broadcast_tx.subscribe();
+        let app_state_rx = app_state_rx.clone();
+        tokio::spawn(async move {
+            ldap_acceptor(starttls_listener, tls_acceptor, broadcast_rx, app_state_rx).await
+        })
+    });
+
     let acceptor = tokio::spawn(async move {
-        ldaps_acceptor(listener, tls_acceptor, broadcast_rx, app_state).await
+        ldaps_acceptor(listener, tls_acceptor, broadcast_rx, app_state_rx).await
     });
 
+    // If configured, check for certificate renewals periodically. When not configured
+    // the ticker is never polled, so its period is irrelevant.
+    let tls_reload_interval = sync_config
+        .tls_reload_interval
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs);
+    let mut tls_reload_ticker =
+        tokio::time::interval(tls_reload_interval.unwrap_or(Duration::from_secs(3600)));
+
     // Finally, block on the signal handler.
     loop {
         tokio::select! {
+            _ = tls_reload_ticker.tick(), if tls_reload_interval.is_some() => {
+                if let Err(err) = cert_resolver.reload_if_modified() {
+                    error!(?err, "Failed to reload tls certificate, continuing with the current certificate");
+                }
+            }
             Ok(()) = tokio::signal::ctrl_c() => {
                 break
             }
@@ -356,7 +687,11 @@ async fn setup(opt: &Opt) {
                 #[allow(clippy::unwrap_used)]
                 tokio::signal::unix::signal(sigterm).unwrap().recv().await
             } => {
-                // Ignore
+                if let Some(applied_config) =
+                    reload_app_state(&opt.config, &sync_config, &app_state_tx, &cert_resolver)
+                {
+                    sync_config = applied_config;
+                }
             }
             Some(()) = async move {
                 let sigterm = tokio::signal::unix::SignalKind::user_defined1();
@@ -382,6 +717,17 @@ async fn setup(opt: &Opt) {
 
     // Wait for tasks to join.
     let _ = acceptor.await;
+    if let Some(starttls_acceptor) = starttls_acceptor {
+        let _ = starttls_acceptor.await;
+    }
+
+    if let Some(cache_snapshot_path) = sync_config.cache_snapshot_path.as_ref() {
+        let app_state = app_state_tx.borrow().clone();
+        match save_cache(&app_state.cache, &app_state.write_log, cache_snapshot_path) {
+            Ok(count) => info!("Saved {} cached results", count),
+            Err(err) => error!(?err, "Unable to save the cache"),
+        }
+    }
 }
 
 #[tokio::main(flavor = "multi_thread")]
@@ -0,0 +1,58 @@
+// Networks are written in CIDR notation, such as `10.0.0.0/8` or `2001:db8::/32`. A
+// single address is a network of only that address.
+
+use serde_with::DeserializeFromStr;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+#[derive(DeserializeFromStr, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl FromStr for IpNetwork {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.trim().split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s.trim(), None),
+        };
+
+        let addr = IpAddr::from_str(addr).map_err(|err| format!("{}: {}", s, err))?;
+        let max_prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => u8::from_str(prefix_len)
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max_prefix_len)
+                .ok_or_else(|| format!("{}: invalid prefix length", s))?,
+            None => max_prefix_len,
+        };
+
+        Ok(IpNetwork { addr, prefix_len })
+    }
+}
+
+impl IpNetwork {
+    /// Check if an address is in the network. IPv4 addresses that are mapped to IPv6,
+    /// as a dual stack listener reports them, are treated as IPv4.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use crate::proxy::{BasicLdapClient, LdapError};
+use crate::upstream::UpstreamSet;
+use ldap3_proto::control::LdapControl;
+use ldap3_proto::proto::{LdapBindCred, LdapBindRequest, LdapBindResponse, LdapResultCode};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tracing::{debug, trace, warn};
+
+/// A pool of upstream connections that are bound as the same mapped identity. Since
+/// every client of a `map_to_dn` / `map_to_secret` configuration becomes the same
+/// upstream identity, these connections can be reused between client sessions.
+pub struct BindPool {
+    map_to_dn: String,
+    map_to_secret: String,
+    upstreams: Arc<UpstreamSet>,
+    // Idle connections and when they were returned. The most recently used are last.
+    idle: Mutex<Vec<(BasicLdapClient, Instant)>>,
+    min_idle: usize,
+    max_idle: usize,
+    idle_timeout: Duration,
+}
+
+impl BindPool {
+    pub fn new(
+        map_to_dn: String,
+        map_to_secret: String,
+        upstreams: Arc<UpstreamSet>,
+        min_idle: usize,
+        max_idle: usize,
+        idle_timeout: Duration,
+    ) -> Self {
+        BindPool {
+            map_to_dn,
+            map_to_secret,
+            upstreams,
+            idle: Mutex::new(Vec::with_capacity(max_idle)),
+            min_idle: min_idle.min(max_idle),
+            max_idle,
+            idle_timeout,
+        }
+    }
+
+    fn take_idle(&self) -> Option<BasicLdapClient> {
+        self.idle
+            .lock()
+            .ok()
+            .and_then(|mut idle| idle.pop())
+            .map(|(client, _)| client)
+    }
+
+    pub fn idle_len(&self) -> usize {
+        self.idle.lock().map(|idle| idle.len()).unwrap_or_default()
+    }
+
+    /// Bind a connection from the pool, or a new connection if none are idle. Idle
+    /// connections are always rebound on checkout, which also confirms they are still
+    /// usable.
+    pub async fn bind(
+        &self,
+        lbr: LdapBindRequest,
+        ctrl: Vec<LdapControl>,
+    ) -> Result<(BasicLdapClient, LdapBindResponse, Vec<LdapControl>), LdapError> {
+        while let Some(client) = self.take_idle() {
+            match client.bind(lbr.clone(), ctrl.clone()).await {
+                Ok((bind_resp, ctrl)) => {
+                    trace!("reusing pooled connection");
+                    return Ok((client, bind_resp, ctrl));
+                }
+                Err(err) => {
+                    debug!(?err, "discarding pooled connection");
+                }
+            }
+        }
+
+        let client = self.upstreams.connect().await?;
+        let (bind_resp, ctrl) = client.bind(lbr, ctrl).await?;
+        Ok((client, bind_resp, ctrl))
+    }
+
+    /// Return a connection to the pool once a client session has finished with it.
+    pub fn checkin(&self, client: BasicLdapClient) {
+        if client.is_failed() {
+            debug!("discarding failed connection");
+            return;
+        }
+
+        if let Ok(mut idle) = self.idle.lock() {
+            if idle.len() < self.max_idle {
+                idle.push((client, Instant::now()));
+            }
+        }
+    }
+
+    /// Open a new connection and bind it as the mapped identity.
+    async fn open(&self) -> Result<BasicLdapClient, LdapError> {
+        let lbr = LdapBindRequest {
+            dn: self.map_to_dn.clone(),
+            cred: LdapBindCred::Simple(self.map_to_secret.clone()),
+        };
+
+        let client = self.upstreams.connect().await?;
+        let (bind_resp, _) = client.bind(lbr, Vec::with_capacity(0)).await?;
+        if bind_resp.res.code != LdapResultCode::Success {
+            warn!(map_to_dn = %self.map_to_dn, res = ?bind_resp.res, "unable to bind pooled connection");
+            return Err(LdapError::InvalidProtocolState);
+        }
+        Ok(client)
+    }
+
+    /// Close connections that have been idle for too long, and then open connections
+    /// until there are at least the minimum number idle.
+    pub async fn maintain(&self) {
+        if let Ok(mut idle) = self.idle.lock() {
+            let now = Instant::now();
+            // The oldest connections are first.
+            let expired = idle
+                .iter()
+                .take_while(|(_, returned)| now.duration_since(*returned) >= self.idle_timeout)
+                .count()
+                .min(idle.len().saturating_sub(self.min_idle));
+            if expired > 0 {
+                debug!(map_to_dn = %self.map_to_dn, %expired, "closing idle pooled connections");
+                idle.drain(..expired);
+            }
+        }
+
+        // New connections are opened, as taking an idle connection to bind would only
+        // return it to the pool.
+        for _ in self.idle_len()..self.min_idle {
+            match self.open().await {
+                Ok(client) => self.checkin(client),
+                Err(err) => {
+                    warn!(map_to_dn = %self.map_to_dn, ?err, "unable to open pooled connection");
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Periodically close idle connections and maintain the minimum pool size. This stops
+/// once the pool is no longer in use, such as after a configuration reload.
+pub async fn pool_maintenance_task(pool: Weak<BindPool>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+
+        let Some(pool) = pool.upgrade() else {
+            break;
+        };
+
+        pool.maintain().await;
+    }
+    debug!("Stopped connection pool maintenance");
+}
@@ -0,0 +1,51 @@
+// Searches that are known to be made often are made by the proxy itself when it starts,
+// and again shortly before their cached results expire, so that clients find them in
+// the cache rather than waiting on the ldap server.
+
+use crate::proxy::refresh_search;
+use crate::{AppState, PrewarmQuery, LDAP_CLIENT_IO_TIMEOUT};
+use std::sync::Weak;
+use std::time::{Duration, Instant};
+use tokio::time::timeout;
+use tracing::{debug, warn};
+
+// The least time between searches, so that a result that expires quickly isn't
+// searched for continuously.
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Keep the result of a search cached, searching again `refresh_ahead` before it
+/// expires, or halfway through the time it is cached for if that is later. This stops
+/// once the app state is no longer in use, such as after a configuration reload.
+pub async fn prewarm_task(app_state: Weak<AppState>, query: PrewarmQuery, refresh_ahead: Duration) {
+    loop {
+        let Some(app_state) = app_state.upgrade() else {
+            break;
+        };
+
+        let searched_at = Instant::now();
+        let valid_until = timeout(LDAP_CLIENT_IO_TIMEOUT, refresh_search(&app_state, &query))
+            .await
+            .unwrap_or_else(|_| {
+                warn!(base = %query.base, "Prewarm search timed out");
+                None
+            });
+        drop(app_state);
+
+        let now = Instant::now();
+        let next = match valid_until {
+            Some(valid_until) => {
+                // Results that are cached for less than refresh_ahead, such as those that
+                // found nothing, would otherwise be searched for continuously.
+                let ttl = valid_until.saturating_duration_since(searched_at);
+                let refresh_in = ttl.saturating_sub(refresh_ahead).max(ttl / 2);
+                (searched_at + refresh_in).max(now + MIN_REFRESH_INTERVAL)
+            }
+            // The result wasn't cached, so it is tried again later.
+            None => now + refresh_ahead.max(MIN_REFRESH_INTERVAL),
+        };
+        debug!(base = %query.base, "Next prewarm search in {:?}", next - now);
+
+        tokio::time::sleep_until(next.into()).await;
+    }
+    debug!(base = %query.base, "Stopped prewarming");
+}
@@ -1,5 +1,9 @@
+use crate::canonical::canonical_search;
+use crate::dn::{canonical_dn, dn_eq, dn_in_subtree, dn_parent};
+use crate::pool::BindPool;
 use crate::{
-    AppState, DnConfig, LdapFilterWrapper, LDAP_CLIENT_CONN_TIMEOUT, LDAP_CLIENT_IO_TIMEOUT,
+    AppState, BindDenialResponse, DenialResponse, DnConfig, GroupPolicy, PrewarmQuery,
+    LDAP_CLIENT_CONN_TIMEOUT, LDAP_CLIENT_IO_TIMEOUT,
 };
 use futures_util::sink::SinkExt;
 use futures_util::stream::StreamExt;
@@ -7,32 +11,106 @@ use ldap3_proto::control::LdapControl;
 use ldap3_proto::proto::*;
 use ldap3_proto::LdapCodec;
 use rustls::pki_types::ServerName;
+use serde::{Deserialize, Serialize};
+use serde_with::base64::Base64;
+use serde_with::serde_as;
+use std::collections::HashMap;
 use std::hash::Hash;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::num::NonZeroUsize;
-use std::sync::Arc;
-use std::time::Instant;
-use tokio::io::{AsyncRead, AsyncWrite};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::io::{ReadHalf, WriteHalf};
-use tokio::net::TcpStream;
-use tokio::time::timeout;
-use tokio_rustls::{client::TlsStream, TlsConnector};
-use tokio_util::codec::{FramedRead, FramedWrite};
-use tracing::{debug, error, info, instrument, trace, warn};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::sync::mpsc;
+use tokio::task::{self, AbortHandle, JoinError, JoinSet};
+use tokio::time::{timeout, timeout_at};
+use tokio_rustls::{client::TlsStream, TlsAcceptor, TlsConnector};
+use tokio_util::codec::{Framed, FramedRead, FramedWrite};
+use tracing::{debug, error, info, instrument, trace, warn, Instrument};
+
+const OID_STARTTLS: &str = "1.3.6.1.4.1.1466.20037";
+
+const STARTTLS_MSGID: i32 = 1;
+
+type CR = ReadHalf<UpstreamStream>;
+type CW = WriteHalf<UpstreamStream>;
+
+// Responses to the client are queued here by the operations that produce them, and
+// written out by the session in the order they were queued.
+type ResponseTx = mpsc::Sender<LdapMsg>;
+
+// The number of responses that may be queued for the client before operations wait for
+// them to be sent.
+const CLIENT_QUEUE_LEN: usize = 64;
+
+#[serde_as]
+#[derive(Debug, Clone, Hash, PartialOrd, Ord, Eq, PartialEq, Serialize, Deserialize)]
+enum CacheRequest {
+    Search(LdapSearchRequest),
+    Compare {
+        dn: String,
+        atype: String,
+        #[serde_as(as = "Base64")]
+        val: Vec<u8>,
+    },
+}
+
+/// Whose results a cached result is. Results are shared by the dns in a cache group,
+/// and otherwise by the dns that bind to the ldap server as the same dn.
+#[derive(Debug, Clone, Hash, PartialOrd, Ord, Eq, PartialEq, Serialize, Deserialize)]
+enum CacheIdentity {
+    Group(String),
+    Upstream(String),
+}
 
-type CR = ReadHalf<TlsStream<TcpStream>>;
-type CW = WriteHalf<TlsStream<TcpStream>>;
+impl CacheIdentity {
+    fn new(upstream_dn: &str, config: &DnConfig) -> Self {
+        match config.cache_group.as_ref() {
+            Some(cache_group) => CacheIdentity::Group(cache_group.clone()),
+            None => CacheIdentity::Upstream(canonical_dn(upstream_dn)),
+        }
+    }
+}
 
-#[derive(Debug, Clone, Hash, PartialOrd, Ord, Eq, PartialEq)]
+#[derive(Debug, Clone, Hash, PartialOrd, Ord, Eq, PartialEq, Serialize, Deserialize)]
 pub struct SearchCacheKey {
-    bind_dn: String,
-    search: LdapSearchRequest,
+    identity: CacheIdentity,
+    request: CacheRequest,
     ctrl: Vec<LdapControl>,
 }
 
+impl SearchCacheKey {
+    /// Check if a write to this dn could change the cached result.
+    pub fn affected_by(&self, dn: &str) -> bool {
+        match &self.request {
+            CacheRequest::Search(sr) => {
+                let in_scope = match sr.scope {
+                    LdapSearchScope::Base => dn_eq(dn, &sr.base),
+                    LdapSearchScope::OneLevel => dn_eq(dn_parent(dn), &sr.base),
+                    LdapSearchScope::Subtree => dn_in_subtree(dn, &sr.base),
+                    LdapSearchScope::Children => {
+                        dn_in_subtree(dn, &sr.base) && !dn_eq(dn, &sr.base)
+                    }
+                };
+                // A write to the base, or above it, may change whether the base exists.
+                in_scope || dn_in_subtree(&sr.base, dn)
+            }
+            CacheRequest::Compare { dn: compare_dn, .. } => dn_in_subtree(compare_dn, dn),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct CachedValue {
     pub valid_until: Instant,
+    /// The generation of the write log from before the result was searched for.
+    pub generation: u64,
     pub entries: Vec<(LdapSearchResultEntry, Vec<LdapControl>)>,
     pub result: LdapResult,
     pub ctrl: Vec<LdapControl>,
@@ -50,13 +128,33 @@ impl CachedValue {
 enum ClientState {
     Unbound,
     Authenticated {
-        dn: String,
+        /// The dn that is bound to the ldap server, after any mapping.
+        upstream_dn: String,
         display_dn: String,
-        config: DnConfig,
-        client: BasicLdapClient,
+        config: Arc<DnConfig>,
+        client: Arc<BasicLdapClient>,
+        bind_pool: Option<Arc<BindPool>>,
     },
 }
 
+impl ClientState {
+    /// Leave this state, returning a pooled connection for reuse. This must only be
+    /// done once no operations are using the connection.
+    fn release(self) {
+        if let ClientState::Authenticated {
+            client,
+            bind_pool: Some(bind_pool),
+            ..
+        } = self
+        {
+            match Arc::try_unwrap(client) {
+                Ok(client) => bind_pool.checkin(client),
+                Err(_) => debug!("connection still in use, not returning it to the pool"),
+            }
+        }
+    }
+}
+
 fn bind_operror(msgid: i32, msg: &str) -> LdapMsg {
     LdapMsg {
         msgid,
@@ -73,37 +171,167 @@ fn bind_operror(msgid: i32, msg: &str) -> LdapMsg {
     }
 }
 
+const DENIED_MESSAGE: &str = "operation is not allowed by policy";
+
+/// The result of an operation that isn't allowed for this dn. An empty successful
+/// result is only possible for searches.
+fn denied_result(app_state: &AppState, config: &DnConfig, is_search: bool) -> LdapResult {
+    let code = match config.denial_response.unwrap_or(app_state.denial_response) {
+        DenialResponse::EmptySuccess if is_search => LdapResultCode::Success,
+        DenialResponse::EmptySuccess | DenialResponse::InsufficientAccessRights => {
+            LdapResultCode::InsufficentAccessRights
+        }
+        DenialResponse::NoSuchObject => LdapResultCode::NoSuchObject,
+        DenialResponse::UnwillingToPerform => LdapResultCode::UnwillingToPerform,
+    };
+
+    let message = if code == LdapResultCode::Success {
+        ""
+    } else {
+        config
+            .denial_message
+            .as_deref()
+            .or(app_state.denial_message.as_deref())
+            .unwrap_or(DENIED_MESSAGE)
+    };
+
+    LdapResult {
+        code,
+        matcheddn: "".to_string(),
+        message: message.to_string(),
+        referral: vec![],
+    }
+}
+
+fn bind_denied_result(app_state: &AppState) -> LdapResult {
+    let (code, message) = match app_state.bind_denial_response {
+        BindDenialResponse::OperationsError => (LdapResultCode::OperationsError, "unable to bind"),
+        BindDenialResponse::InvalidCredentials => {
+            (LdapResultCode::InvalidCredentials, DENIED_MESSAGE)
+        }
+        BindDenialResponse::InsufficientAccessRights => {
+            (LdapResultCode::InsufficentAccessRights, DENIED_MESSAGE)
+        }
+        BindDenialResponse::UnwillingToPerform => {
+            (LdapResultCode::UnwillingToPerform, DENIED_MESSAGE)
+        }
+    };
+
+    let message = app_state.denial_message.as_deref().unwrap_or(message);
+
+    LdapResult {
+        code,
+        matcheddn: "".to_string(),
+        message: message.to_string(),
+        referral: vec![],
+    }
+}
+
+async fn send_bind_denied(
+    w: &ResponseTx,
+    app_state: &AppState,
+    msgid: i32,
+) -> Result<(), LdapError> {
+    let resp_msg = LdapMsg {
+        msgid,
+        op: LdapOp::BindResponse(LdapBindResponse {
+            res: bind_denied_result(app_state),
+            saslcreds: None,
+        }),
+        ctrl: vec![],
+    };
+    w.send(resp_msg).await.map_err(|err| {
+        error!(?err, "Unable to send response");
+        LdapError::Transport
+    })
+}
+
+/// Choose the config of a bound dn from the groups it is a member of. The groups are
+/// looked up with the dn's own connection, unless its policy is cached.
+async fn select_group_policy(
+    client: &BasicLdapClient,
+    group_policy: &GroupPolicy,
+    dn: &str,
+) -> Result<Option<DnConfig>, LdapError> {
+    let policy = match group_policy.cached(dn) {
+        Some(policy) => policy,
+        None => {
+            let mut groups = Vec::new();
+            let mut search_stream =
+                client.search(group_policy.search_request(dn), Vec::with_capacity(0))?;
+            loop {
+                match search_stream.next().await? {
+                    SearchItem::Entry(entry, _) => groups.extend(group_policy.entry_groups(&entry)),
+                    SearchItem::Done(result, _) => {
+                        if result.code != LdapResultCode::Success {
+                            // This isn't cached, so the groups are looked up again on
+                            // the next bind.
+                            warn!(code = ?result.code, "The group search of {} failed", dn);
+                            break group_policy.select(&groups);
+                        }
+                        debug!(?groups);
+                        let policy = group_policy.select(&groups);
+                        group_policy.cache_policy(dn, policy.clone());
+                        break policy;
+                    }
+                }
+            }
+        }
+    };
+
+    let Some(policy) = policy else {
+        return Ok(None);
+    };
+    info!("Group policy {} is used for {}", policy, dn);
+    Ok(group_policy.policy(&policy).cloned())
+}
+
+/// Bind a client. The client's address is checked against the allowed networks of its
+/// dn, unless it is `None`, for binds made by the proxy itself.
 #[instrument(level = "info", skip_all)]
-async fn bind<W: AsyncWrite + Unpin>(
-    w: &mut FramedWrite<W, LdapCodec>,
+async fn bind(
+    w: &ResponseTx,
     app_state: &AppState,
+    client_ip: Option<IpAddr>,
     mut lbr: LdapBindRequest,
     msgid: i32,
     ctrl: Vec<LdapControl>,
 ) -> Result<Option<ClientState>, LdapError> {
     trace!(?lbr);
     // Is the requested bind dn valid per our map?
-    let config = match app_state.binddn_map.get(&lbr.dn) {
+    let mut group_policy = None;
+    let mut config = match app_state.binddn_map.get(&lbr.dn) {
         Some(dnconfig) => {
             // They have a config! They can proceed.
             dnconfig.clone()
         }
         None => {
-            if app_state.allow_all_bind_dns {
+            if let Some(policy) = app_state
+                .group_policy
+                .as_ref()
+                .filter(|_| !lbr.dn.is_empty())
+            {
+                // Their config is chosen from their groups once they have bound.
+                group_policy = Some(policy);
+                DnConfig::default()
+            } else if app_state.allow_all_bind_dns {
                 // All bind dns are allow, return a default config.
                 DnConfig::default()
             } else {
                 // Bind dns are filtered, sad trombone time.
-                let resp_msg = bind_operror(msgid, "unable to bind");
-                w.send(resp_msg).await.map_err(|err| {
-                    error!(?err, "Unable to send response");
-                    LdapError::Transport
-                })?;
+                warn!("Bind is not allowed for {}", lbr.dn);
+                send_bind_denied(w, app_state, msgid).await?;
                 return Ok(None);
             }
         }
     };
 
+    if let Some(client_ip) = client_ip.filter(|client_ip| !config.address_allowed(*client_ip)) {
+        warn!("Bind is not allowed for {} from {}", lbr.dn, client_ip);
+        send_bind_denied(w, app_state, msgid).await?;
+        return Ok(None);
+    }
+
     // Okay, we have a dnconfig, so they are allowed to proceed. Lets
     // now setup the client for their session, and anything else we
     // need to configure.
@@ -120,6 +348,7 @@ async fn bind<W: AsyncWrite + Unpin>(
             lbr.cred = LdapBindCred::Simple(map_to_secret);
         }
     };
+    let upstream_dn = lbr.dn.clone();
 
     let display_dn = if dn.is_empty() {
         "anonymous"
@@ -133,43 +362,57 @@ async fn bind<W: AsyncWrite + Unpin>(
         display_dn.to_string()
     };
 
-    // We need the client to connect *and* bind to proceed here!
-    let mut client = match BasicLdapClient::build(
-        &app_state.addrs,
-        &app_state.tls_hostname,
-        &app_state.tls_connector,
-        app_state.max_proxy_ber_size,
-    )
-    .await
-    {
-        Ok(c) => c,
-        Err(e) => {
-            error!(?e, "A client build error has occurred.");
-            let resp_msg = bind_operror(msgid, "unable to bind");
-            w.send(resp_msg).await.map_err(|err| {
-                error!(?err, "Unable to send response");
-                LdapError::Transport
-            })?;
-            // Always bail.
-            return Ok(None);
+    // A mapped identity with a secret is the same for every client, so a pooled
+    // connection can be used. It is rebound here all the same.
+    let bind_pool = match (config.map_to_dn.as_ref(), config.map_to_secret.as_ref()) {
+        (Some(map_to_dn), Some(map_to_secret)) => app_state
+            .bind_pools
+            .get(&(map_to_dn.clone(), map_to_secret.clone()))
+            .cloned(),
+        _ => None,
+    };
+
+    let bind_result = if let Some(bind_pool) = bind_pool.as_ref() {
+        bind_pool.bind(lbr, ctrl).await
+    } else {
+        // We need the client to connect *and* bind to proceed here!
+        match app_state.upstreams.connect().await {
+            Ok(client) => client
+                .bind(lbr, ctrl)
+                .await
+                .map(|(bind_resp, ctrl)| (client, bind_resp, ctrl)),
+            Err(e) => Err(e),
         }
     };
 
-    let valid = match client.bind(lbr, ctrl).await {
-        Ok((bind_resp, ctrl)) => {
-            // Almost there, lets check the bind result.
-            let valid = bind_resp.res.code == LdapResultCode::Success;
+    // The credential of a dn that is mapped with a secret is replaced by the proxy, so
+    // the ldap server doesn't check it. While the ldap server can't be reached such a
+    // dn may still bind, so that it can be sent stale results.
+    let bind_offline = config.map_to_dn.is_some()
+        && config.map_to_secret.is_some()
+        && !app_state.cache_stale_if_error.is_zero();
 
-            let resp_msg = LdapMsg {
-                msgid,
-                op: LdapOp::BindResponse(bind_resp),
-                ctrl,
+    let (client, bind_resp, ctrl) = match bind_result {
+        Ok(bind_result) => bind_result,
+        Err(LdapError::ConnectError) if bind_offline => {
+            warn!(
+                "The ldap server can't be reached, {} is bound only to be sent cached results",
+                display_dn
+            );
+            let bind_resp = LdapBindResponse {
+                res: LdapResult {
+                    code: LdapResultCode::Success,
+                    matcheddn: "".to_string(),
+                    message: "".to_string(),
+                    referral: vec![],
+                },
+                saslcreds: None,
             };
-            w.send(resp_msg).await.map_err(|err| {
-                error!(?err, "Unable to send response");
-                LdapError::Transport
-            })?;
-            valid
+            (
+                BasicLdapClient::disconnected(),
+                bind_resp,
+                Vec::with_capacity(0),
+            )
         }
         Err(e) => {
             error!(?e, "A client bind error has occurred");
@@ -183,13 +426,58 @@ async fn bind<W: AsyncWrite + Unpin>(
         }
     };
 
+    // Almost there, lets check the bind result.
+    let valid = bind_resp.res.code == LdapResultCode::Success;
+
+    if let Some(group_policy) = group_policy.filter(|_| valid) {
+        match select_group_policy(&client, group_policy, &dn).await {
+            // The networks of a policy can only be checked once it is chosen.
+            Ok(Some(policy_config)) => {
+                if let Some(client_ip) =
+                    client_ip.filter(|client_ip| !policy_config.address_allowed(*client_ip))
+                {
+                    warn!("Bind is not allowed for {} from {}", display_dn, client_ip);
+                    send_bind_denied(w, app_state, msgid).await?;
+                    return Ok(None);
+                }
+                config = policy_config;
+            }
+            Ok(None) if app_state.allow_all_bind_dns => {}
+            Ok(None) => {
+                warn!("Bind is not allowed for {} by group policy", display_dn);
+                send_bind_denied(w, app_state, msgid).await?;
+                return Ok(None);
+            }
+            Err(e) => {
+                error!(?e, "Unable to look up the groups of {}", display_dn);
+                let resp_msg = bind_operror(msgid, "unable to bind");
+                w.send(resp_msg).await.map_err(|err| {
+                    error!(?err, "Unable to send response");
+                    LdapError::Transport
+                })?;
+                return Ok(None);
+            }
+        }
+    }
+
+    let resp_msg = LdapMsg {
+        msgid,
+        op: LdapOp::BindResponse(bind_resp),
+        ctrl,
+    };
+    w.send(resp_msg).await.map_err(|err| {
+        error!(?err, "Unable to send response");
+        LdapError::Transport
+    })?;
+
     if valid {
         info!("Successful bind for {}", display_dn);
         Ok(Some(ClientState::Authenticated {
-            dn,
+            upstream_dn,
             display_dn,
-            config,
-            client,
+            config: Arc::new(config),
+            client: Arc::new(client),
+            bind_pool,
         }))
     } else {
         Ok(None)
@@ -204,75 +492,150 @@ struct SearchRequest<'a> {
     msgid: i32,
     ctrl: Vec<LdapControl>,
 
-    dn: &'a str,
+    upstream_dn: &'a str,
     display_dn: &'a str,
-    config: &'a DnConfig,
-    client: &'a mut BasicLdapClient,
+    config: &'a Arc<DnConfig>,
+    client: &'a Arc<BasicLdapClient>,
+
+    /// Search the ldap server even if the result is cached, replacing it.
+    refresh: bool,
+}
+
+/// A search of the ldap server that is allowed for the dn, and restricted to what it
+/// may see.
+struct UpstreamSearch {
+    sr: LdapSearchRequest,
+    msgid: i32,
+    ctrl: Vec<LdapControl>,
+    cache_key: SearchCacheKey,
+    config: Arc<DnConfig>,
+    client: Arc<BasicLdapClient>,
+}
+
+/// How a search of the ldap server ended.
+enum UpstreamResult {
+    /// The result was sent, and was cached until the instant if it could be.
+    Done(Option<Instant>),
+    /// The ldap server couldn't be searched, after this many entries were sent. No
+    /// result was sent.
+    Failed(usize),
+}
+
+/// A cached result, and how long ago it expired if it has.
+enum CacheLookup {
+    Miss,
+    Fresh(CachedValue),
+    /// The result may be sent while it is searched for again.
+    Revalidate(CachedValue, Duration),
+    /// The result may be sent if the ldap server can't be searched.
+    StaleIfError(CachedValue, Duration),
+}
+
+fn lookup_cache(app_state: &AppState, cache_key: &SearchCacheKey, now: Instant) -> CacheLookup {
+    let mut cache_read_txn = app_state.cache.read();
+    let Some(cache_value) = cache_read_txn.get(cache_key) else {
+        return CacheLookup::Miss;
+    };
+
+    if app_state
+        .write_log
+        .invalidates(cache_value.generation, cache_key)
+    {
+        debug!("Cache item was changed by a write");
+        return CacheLookup::Miss;
+    }
+
+    if cache_value.valid_until > now {
+        return CacheLookup::Fresh(cache_value.clone());
+    }
+
+    let expired_for = now - cache_value.valid_until;
+    if expired_for < app_state.cache_stale_while_revalidate {
+        CacheLookup::Revalidate(cache_value.clone(), expired_for)
+    } else if expired_for < app_state.cache_stale_if_error {
+        CacheLookup::StaleIfError(cache_value.clone(), expired_for)
+    } else {
+        debug!("Cache item expired");
+        CacheLookup::Miss
+    }
 }
 
+/// Answer a search, returning when the result that was cached expires, if one was.
 #[instrument(level = "info", skip_all)]
-async fn search<W: AsyncWrite + Unpin>(
-    w: &mut FramedWrite<W, LdapCodec>,
-    app_state: &AppState,
+async fn search(
+    w: &ResponseTx,
+    app_state: &Arc<AppState>,
     search_request: SearchRequest<'_>,
-) -> Result<(), LdapError> {
+) -> Result<Option<Instant>, LdapError> {
     let SearchRequest {
         sr,
         msgid,
         ctrl,
-        dn,
+        upstream_dn,
         display_dn,
         config,
         client,
+        refresh,
     } = search_request;
 
+    // Searches that are the same are checked and cached alike, but the ldap server is
+    // sent the search as the client made it.
+    let canonical = canonical_search(sr.clone());
+
     // Pre check if the search is allowed for this dn / scope / filter
-    if config.allowed_queries.is_empty() {
+    let allowed = if !config.queries_restricted() {
         // All queries are allowed.
         debug!("All queries are allowed");
+        true
+    } else if config.query_allowed(&canonical.base, &canonical.scope, &canonical.filter) {
+        // Good to proceed.
+        debug!("Query is granted");
+        true
     } else {
-        // Let's check the query details.
-        let allow_key = (
-            sr.base.clone(),
-            sr.scope.clone(),
-            LdapFilterWrapper {
-                inner: sr.filter.clone(),
-            },
+        warn!(
+            base = %canonical.base,
+            scope = ?canonical.scope,
+            filter = ?canonical.filter,
+            "Requested query is not allowed for {}", display_dn
         );
+        false
+    };
 
-        if config.allowed_queries.contains(&allow_key) {
-            // Good to proceed.
-            debug!("Query is granted");
-        } else {
+    // The filter must not test attributes that this dn can't see.
+    let allowed = allowed && {
+        let filter_allowed = config.filter_allowed(&canonical.filter);
+        if !filter_allowed {
             warn!(
-                ?allow_key,
-                "Requested query is not allowed for {}", display_dn
+                filter = ?canonical.filter,
+                "Requested filter uses attributes that are not allowed for {}", display_dn
             );
-            // If not, send an empty result.
-            w.send(LdapMsg {
-                msgid,
-                op: LdapOp::SearchResultDone(LdapResult {
-                    code: LdapResultCode::Success,
-                    matcheddn: "".to_string(),
-                    message: "".to_string(),
-                    referral: vec![],
-                }),
-                ctrl,
-            })
-            .await
-            .map_err(|err| {
-                error!(?err, "Unable to send response");
-                LdapError::Transport
-            })?;
-
-            return Ok(());
         }
+        filter_allowed
     };
 
+    if !allowed {
+        // If not, send an empty result, or the configured denial.
+        w.send(LdapMsg {
+            msgid,
+            op: LdapOp::SearchResultDone(denied_result(app_state, config, true)),
+            ctrl,
+        })
+        .await
+        .map_err(|err| {
+            error!(?err, "Unable to send response");
+            LdapError::Transport
+        })?;
+
+        return Ok(None);
+    }
+
+    // Only request the attributes and entries that this dn may see.
+    let sr = restrict_search(config, sr);
+
     // This is done like this to facilitate a cache mechanism in future.
     //
     // Cache will need to key on:
-    //    bind_dn
+    //    upstream identity or cache group
     //    base
     //    scope
     //    deref aliases
@@ -284,85 +647,612 @@ async fn search<W: AsyncWrite + Unpin>(
     // Which is a lot, but it's everything that controls to results to
     // ensure we don't introduce corruption.
 
-    let now = Instant::now();
-
-    // get the read txn.
-    let mut cache_read_txn = app_state.cache.read();
-
     let cache_key = SearchCacheKey {
-        bind_dn: dn.to_string(),
-        search: sr.clone(),
+        identity: CacheIdentity::new(upstream_dn, config),
+        request: CacheRequest::Search(restrict_search(config, canonical)),
         ctrl: ctrl.clone(),
     };
     debug!(?cache_key);
 
-    let maybe_results = cache_read_txn.get(&cache_key).and_then(|cache_value| {
-        if cache_value.valid_until > now {
-            Some(cache_value.clone())
-        } else {
-            debug!("Cache item expired");
-            None
+    let cache_lookup = if refresh {
+        CacheLookup::Miss
+    } else {
+        lookup_cache(app_state, &cache_key, Instant::now())
+    };
+
+    let upstream_search = UpstreamSearch {
+        sr,
+        msgid,
+        ctrl,
+        cache_key,
+        config: config.clone(),
+        client: client.clone(),
+    };
+
+    let stale_value = match cache_lookup {
+        CacheLookup::Fresh(cache_value) => {
+            debug!("cache hit true");
+            app_state.cache_stats.record_hit();
+            send_cached(w, msgid, config, cache_value).await?;
+
+            app_state.cache.try_quiesce();
+            return Ok(None);
         }
-    });
+        CacheLookup::Revalidate(cache_value, expired_for) => {
+            info!(
+                "Sending a result that expired {:?} ago to {} while it is refreshed",
+                expired_for, display_dn
+            );
+            app_state.cache_stats.record_stale_while_revalidate();
+            send_cached(w, msgid, config, cache_value).await?;
+            revalidate(app_state, upstream_search);
+
+            app_state.cache.try_quiesce();
+            return Ok(None);
+        }
+        CacheLookup::StaleIfError(cache_value, expired_for) => Some((cache_value, expired_for)),
+        CacheLookup::Miss => None,
+    };
 
-    let was_cache_miss = maybe_results.is_none();
+    debug!("cache hit false");
+    if !refresh {
+        app_state.cache_stats.record_miss();
+    }
 
-    debug!("cache hit {}", !was_cache_miss);
+    match search_upstream(w, app_state, upstream_search, refresh).await? {
+        UpstreamResult::Done(valid_until) => Ok(valid_until),
+        // Entries of the new result may have been sent, so the stale result can't be.
+        UpstreamResult::Failed(sent) => match stale_value.filter(|_| sent == 0) {
+            Some((cache_value, expired_for)) => {
+                warn!(
+                    "The ldap server can't be searched, sending a result that expired {:?} ago to {}",
+                    expired_for, display_dn
+                );
+                app_state.cache_stats.record_stale_if_error();
+                send_cached(w, msgid, config, cache_value).await?;
+                Ok(None)
+            }
+            None => send_search_error(w, msgid).await.map(|_| None),
+        },
+    }
+}
 
-    let (entries, result, ctrl) = match maybe_results {
-        Some(CachedValue {
-            valid_until: _,
-            entries,
-            result,
-            ctrl,
-        }) => (entries, result, ctrl),
-        None => {
-            match client.search(sr, ctrl).await {
-                Ok(data) => data,
-                Err(e) => {
-                    error!(?e, "A client search error has occurred");
-                    let resp_msg = bind_operror(msgid, "unable to search");
-                    w.send(resp_msg).await.map_err(|err| {
-                        error!(?err, "Unable to send response");
-                        LdapError::Transport
-                    })?;
-
-                    // Error sent, return with no state change.
-                    return Ok(());
+/// The search limited to the attributes and entries that this dn may see.
+fn restrict_search(config: &DnConfig, sr: LdapSearchRequest) -> LdapSearchRequest {
+    LdapSearchRequest {
+        attrs: config.requested_attrs(&sr.attrs),
+        filter: config.search_filter(sr.filter),
+        sizelimit: config.size_limit(sr.sizelimit),
+        timelimit: config.time_limit(sr.timelimit),
+        ..sr
+    }
+}
+
+async fn send_cached(
+    w: &ResponseTx,
+    msgid: i32,
+    config: &DnConfig,
+    cache_value: CachedValue,
+) -> Result<(), LdapError> {
+    let CachedValue {
+        valid_until: _,
+        generation: _,
+        entries,
+        result,
+        ctrl,
+    } = cache_value;
+
+    for (mut entry, ctrl) in entries {
+        // The entries may have been cached for another dn that shares the cache.
+        config.filter_entry(&mut entry);
+        send_search_entry(w, msgid, entry, ctrl).await?;
+    }
+    send_search_done(w, msgid, result, ctrl).await
+}
+
+/// Search the ldap server, sending the entries as they arrive and caching the result.
+/// A refreshed result replaces the one that is cached.
+async fn search_upstream(
+    w: &ResponseTx,
+    app_state: &AppState,
+    upstream_search: UpstreamSearch,
+    refresh: bool,
+) -> Result<UpstreamResult, LdapError> {
+    let UpstreamSearch {
+        sr,
+        msgid,
+        ctrl,
+        cache_key,
+        config,
+        client,
+    } = upstream_search;
+
+    let now = Instant::now();
+    // Writes made while the search is in progress may not be in its result.
+    let generation = app_state.write_log.generation();
+
+    // The limits are enforced here as well, in case the ldap server ignores them.
+    let size_limit = usize::try_from(sr.sizelimit)
+        .ok()
+        .filter(|limit| *limit > 0);
+    let deadline = u64::try_from(sr.timelimit)
+        .ok()
+        .filter(|limit| *limit > 0)
+        .map(|limit| tokio::time::Instant::now() + Duration::from_secs(limit));
+
+    let mut search_stream = match client.search(sr, ctrl) {
+        Ok(search_stream) => search_stream,
+        Err(e) => {
+            error!(?e, "A client search error has occurred");
+            return Ok(UpstreamResult::Failed(0));
+        }
+    };
+
+    // Entries are sent as they arrive, and a copy is kept for the cache until the
+    // result is too large to be cached.
+    let mut cache_size = std::mem::size_of::<CachedValue>();
+    let mut cache_entries = Some(Vec::new());
+    let mut sent = 0;
+
+    let (result, ctrl) = loop {
+        let item = match deadline {
+            Some(deadline) => match timeout_at(deadline, search_stream.next()).await {
+                Ok(item) => item,
+                Err(_) => {
+                    debug!("Search time limit exceeded");
+                    // The result depends on how quickly the ldap server answered.
+                    cache_entries = None;
+                    break (limit_exceeded(LdapResultCode::TimeLimitExceeded), vec![]);
                 }
+            },
+            None => search_stream.next().await,
+        };
+
+        match item {
+            Ok(SearchItem::Entry(_, _)) if size_limit.is_some_and(|limit| sent >= limit) => {
+                debug!("Search size limit exceeded");
+                break (limit_exceeded(LdapResultCode::SizeLimitExceeded), vec![]);
+            }
+            Ok(SearchItem::Entry(mut entry, ctrl)) => {
+                // The ldap server may return attributes that weren't requested.
+                config.filter_entry(&mut entry);
+
+                if let Some(entries) = cache_entries.as_mut() {
+                    cache_size += entry.size();
+                    if cache_size > app_state.cache_max_entry_bytes {
+                        debug!("Search result is too large to cache");
+                        cache_entries = None;
+                    } else {
+                        entries.push((entry.clone(), ctrl.clone()));
+                    }
+                }
+                send_search_entry(w, msgid, entry, ctrl).await?;
+                sent += 1;
+            }
+            Ok(SearchItem::Done(result, ctrl)) => break (result, ctrl),
+            Err(e) => {
+                error!(?e, "A client search error has occurred");
+                return Ok(UpstreamResult::Failed(sent));
             }
         }
     };
 
-    // Update cache if needed.
-    if was_cache_miss {
+    // The search is abandoned if it was stopped by a limit.
+    drop(search_stream);
+
+    let cache_entries = cache_entries.and_then(|entries| {
+        cache_timeout(app_state, &result.code, entries.is_empty())
+            .map(|cache_timeout| (entries, cache_timeout))
+    });
+
+    let mut valid_until = None;
+    if let Some((entries, cache_timeout)) = cache_entries {
         let cache_value = CachedValue {
-            valid_until: now + app_state.cache_entry_timeout,
-            entries: entries.clone(),
+            valid_until: now + cache_timeout,
+            generation,
+            entries,
             result: result.clone(),
             ctrl: ctrl.clone(),
         };
         if let Some(cache_value_size) = NonZeroUsize::new(cache_value.size()) {
             debug!("Adding entry of size {} to cache", cache_value_size);
-            cache_read_txn.insert_sized(cache_key, cache_value, cache_value_size);
+            valid_until = Some(cache_value.valid_until);
+            if refresh {
+                // A read transaction only adds values that aren't cached, so the
+                // value being refreshed is replaced with a write.
+                let mut cache_write_txn = app_state.cache.write();
+                cache_write_txn.insert_sized(cache_key, cache_value, cache_value_size);
+                cache_write_txn.commit();
+            } else {
+                let mut cache_read_txn = app_state.cache.read();
+                cache_read_txn.insert_sized(cache_key, cache_value, cache_value_size);
+            }
         } else {
             error!("Invalid entry size, unable to add to cache");
         }
     }
 
-    for (entry, ctrl) in entries {
-        w.send(LdapMsg {
-            msgid,
-            op: LdapOp::SearchResultEntry(entry),
-            ctrl,
-        })
-        .await
-        .map_err(|err| {
-            error!(?err, "Unable to send response");
-            LdapError::Transport
-        })?;
+    send_search_done(w, msgid, result, ctrl).await?;
+
+    // Try and quiesce now.
+    app_state.cache.try_quiesce();
+
+    // No state change
+    Ok(UpstreamResult::Done(valid_until))
+}
+
+/// Search for an expired result again in the background, unless it already is being.
+fn revalidate(app_state: &Arc<AppState>, upstream_search: UpstreamSearch) {
+    let cache_key = upstream_search.cache_key.clone();
+    let refreshing = app_state
+        .cache_refreshing
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .insert(cache_key.clone());
+    if !refreshing {
+        debug!("Expired result is already being refreshed");
+        return;
+    }
+
+    let app_state = app_state.clone();
+    tokio::spawn(
+        async move {
+            let resp_tx = discard_responses();
+            let search = search_upstream(&resp_tx, &app_state, upstream_search, true);
+            match timeout(LDAP_CLIENT_IO_TIMEOUT, search).await {
+                Ok(Ok(UpstreamResult::Done(_))) => debug!("Refreshed expired result"),
+                _ => warn!("Unable to refresh expired result"),
+            }
+
+            app_state
+                .cache_refreshing
+                .lock()
+                .unwrap_or_else(|err| err.into_inner())
+                .remove(&cache_key);
+        }
+        .in_current_span(),
+    );
+}
+
+/// A queue for the responses of searches that the proxy makes itself, which are
+/// discarded.
+fn discard_responses() -> ResponseTx {
+    let (resp_tx, mut resp_rx) = mpsc::channel(CLIENT_QUEUE_LEN);
+    tokio::spawn(async move { while resp_rx.recv().await.is_some() {} });
+    resp_tx
+}
+
+/// Make a prewarmed search as a client would, replacing its cached result. This
+/// returns when the new result expires, if it was cached.
+#[instrument(level = "info", skip_all, fields(base = %query.base))]
+pub async fn refresh_search(app_state: &Arc<AppState>, query: &PrewarmQuery) -> Option<Instant> {
+    let resp_tx = discard_responses();
+
+    let lbr = LdapBindRequest {
+        dn: query.bind_dn.clone(),
+        cred: LdapBindCred::Simple(query.bind_secret.clone().unwrap_or_default()),
+    };
+
+    // The search is made by the proxy itself, so the allowed networks don't apply.
+    let state = match bind(&resp_tx, app_state, None, lbr, 0, Vec::default()).await {
+        Ok(Some(state)) => Some(state),
+        _ => {
+            warn!("Unable to bind as {} to prewarm the cache", query.bind_dn);
+            None
+        }
+    };
+
+    let mut valid_until = None;
+    if let Some(state) = state {
+        if let ClientState::Authenticated {
+            upstream_dn,
+            display_dn,
+            config,
+            client,
+            bind_pool: _,
+        } = &state
+        {
+            let search_req = SearchRequest {
+                sr: query.search_request(),
+                msgid: 1,
+                ctrl: Vec::with_capacity(0),
+                upstream_dn,
+                display_dn,
+                config,
+                client,
+                refresh: true,
+            };
+            valid_until = search(&resp_tx, app_state, search_req).await.ok().flatten();
+        }
+        state.release();
+    }
+
+    valid_until
+}
+
+/// How long a result may be cached for, if it may be cached at all. Results that
+/// found nothing are kept for less time, as they may be caused by a passing fault.
+fn cache_timeout(app_state: &AppState, code: &LdapResultCode, empty: bool) -> Option<Duration> {
+    if !app_state.cache_result_codes.contains(code) {
+        debug!(?code, "Result is not cacheable");
+        return None;
+    }
+
+    if empty || *code == LdapResultCode::NoSuchObject {
+        Some(app_state.cache_negative_timeout)
+    } else {
+        Some(app_state.cache_entry_timeout)
+    }
+}
+
+struct CompareRequest<'a> {
+    cr: LdapCompareRequest,
+    msgid: i32,
+    ctrl: Vec<LdapControl>,
+
+    upstream_dn: &'a str,
+    display_dn: &'a str,
+    config: &'a DnConfig,
+    client: &'a BasicLdapClient,
+}
+
+#[instrument(level = "info", skip_all)]
+async fn compare(
+    w: &ResponseTx,
+    app_state: &AppState,
+    compare_request: CompareRequest<'_>,
+) -> Result<(), LdapError> {
+    let CompareRequest {
+        cr,
+        msgid,
+        ctrl,
+        upstream_dn,
+        display_dn,
+        config,
+        client,
+    } = compare_request;
+
+    if config.compare_allowed(&cr.dn, &cr.atype) {
+        debug!("Compare is granted");
+    } else {
+        warn!(
+            dn = %cr.dn,
+            atype = %cr.atype,
+            "Requested compare is not allowed for {}", display_dn
+        );
+        return send_compare_result(w, msgid, denied_result(app_state, config, false), ctrl).await;
+    }
+
+    let cache_key = app_state.cache_compares.then(|| SearchCacheKey {
+        identity: CacheIdentity::new(upstream_dn, config),
+        request: CacheRequest::Compare {
+            dn: cr.dn.clone(),
+            atype: cr.atype.clone(),
+            val: cr.val.clone(),
+        },
+        ctrl: ctrl.clone(),
+    });
+
+    let now = Instant::now();
+    // Writes made while the compare is in progress may not be in its result.
+    let generation = app_state.write_log.generation();
+
+    let mut cache_read_txn = app_state.cache.read();
+
+    if let Some(cache_key) = cache_key.as_ref() {
+        debug!(?cache_key);
+        let maybe_result = cache_read_txn
+            .get(cache_key)
+            .filter(|cache_value| {
+                cache_value.valid_until > now
+                    && !app_state
+                        .write_log
+                        .invalidates(cache_value.generation, cache_key)
+            })
+            .map(|cache_value| (cache_value.result.clone(), cache_value.ctrl.clone()));
+
+        debug!("cache hit {}", maybe_result.is_some());
+
+        if let Some((result, ctrl)) = maybe_result {
+            send_compare_result(w, msgid, result, ctrl).await?;
+
+            app_state.cache.try_quiesce();
+            return Ok(());
+        }
+    }
+
+    let (result, ctrl) = match client.compare(cr, ctrl).await {
+        Ok(data) => data,
+        Err(e) => {
+            error!(?e, "A client compare error has occurred");
+            let resp_msg = LdapMsg {
+                msgid,
+                op: LdapOp::CompareResult(LdapResult {
+                    code: LdapResultCode::OperationsError,
+                    matcheddn: "".to_string(),
+                    message: "unable to compare".to_string(),
+                    referral: vec![],
+                }),
+                ctrl: vec![],
+            };
+            return w.send(resp_msg).await.map_err(|err| {
+                error!(?err, "Unable to send response");
+                LdapError::Transport
+            });
+        }
+    };
+
+    let cache_timeout = cache_timeout(app_state, &result.code, false);
+
+    if let Some((cache_key, cache_timeout)) = cache_key.zip(cache_timeout) {
+        let cache_value = CachedValue {
+            valid_until: now + cache_timeout,
+            generation,
+            entries: Vec::with_capacity(0),
+            result: result.clone(),
+            ctrl: ctrl.clone(),
+        };
+        if let Some(cache_value_size) = NonZeroUsize::new(cache_value.size()) {
+            debug!("Adding entry of size {} to cache", cache_value_size);
+            cache_read_txn.insert_sized(cache_key, cache_value, cache_value_size);
+        } else {
+            error!("Invalid entry size, unable to add to cache");
+        }
+    }
+
+    send_compare_result(w, msgid, result, ctrl).await?;
+
+    app_state.cache.try_quiesce();
+
+    Ok(())
+}
+
+async fn send_compare_result(
+    w: &ResponseTx,
+    msgid: i32,
+    result: LdapResult,
+    ctrl: Vec<LdapControl>,
+) -> Result<(), LdapError> {
+    w.send(LdapMsg {
+        msgid,
+        op: LdapOp::CompareResult(result),
+        ctrl,
+    })
+    .await
+    .map_err(|err| {
+        error!(?err, "Unable to send response");
+        LdapError::Transport
+    })
+}
+
+struct WriteRequest<'a> {
+    op: LdapOp,
+    msgid: i32,
+    ctrl: Vec<LdapControl>,
+
+    display_dn: &'a str,
+    config: &'a DnConfig,
+    client: &'a BasicLdapClient,
+}
+
+/// The dns of the entries that a write changes. A rename changes the entry at both
+/// its former and new dn.
+fn write_targets(op: &LdapOp) -> Vec<String> {
+    match op {
+        LdapOp::AddRequest(req) => vec![req.dn.clone()],
+        LdapOp::ModifyRequest(req) => vec![req.dn.clone()],
+        LdapOp::DelRequest(dn) => vec![dn.clone()],
+        LdapOp::ModifyDNRequest(req) => {
+            let new_parent = req
+                .new_superior
+                .as_deref()
+                .unwrap_or_else(|| dn_parent(&req.dn));
+            let new_dn = if new_parent.is_empty() {
+                req.newrdn.clone()
+            } else {
+                format!("{},{}", req.newrdn, new_parent)
+            };
+            vec![req.dn.clone(), new_dn]
+        }
+        _ => Vec::with_capacity(0),
     }
+}
+
+#[instrument(level = "info", skip_all)]
+async fn write(
+    w: &ResponseTx,
+    app_state: &AppState,
+    write_request: WriteRequest<'_>,
+) -> Result<(), LdapError> {
+    let WriteRequest {
+        op,
+        msgid,
+        ctrl,
+        display_dn,
+        config,
+        client,
+    } = write_request;
 
+    let respond = result_response(&op);
+    let targets = write_targets(&op);
+
+    if config.write_allowed(&op) {
+        debug!("Write is granted");
+    } else {
+        warn!(
+            ?targets,
+            "Requested write is not allowed for {}", display_dn
+        );
+        return w
+            .send(LdapMsg {
+                msgid,
+                op: respond(denied_result(app_state, config, false)),
+                ctrl,
+            })
+            .await
+            .map_err(|err| {
+                error!(?err, "Unable to send response");
+                LdapError::Transport
+            });
+    }
+
+    let resp_msg = match client.write(op, ctrl).await {
+        Ok((result, ctrl)) => {
+            if result.code == LdapResultCode::Success {
+                info!(?targets, "Successful write by {}", display_dn);
+                app_state.write_log.record(targets);
+            }
+            LdapMsg {
+                msgid,
+                op: respond(result),
+                ctrl,
+            }
+        }
+        Err(e) => {
+            error!(?e, "A client write error has occurred");
+            LdapMsg {
+                msgid,
+                op: respond(LdapResult {
+                    code: LdapResultCode::OperationsError,
+                    matcheddn: "".to_string(),
+                    message: "unable to write".to_string(),
+                    referral: vec![],
+                }),
+                ctrl: vec![],
+            }
+        }
+    };
+
+    w.send(resp_msg).await.map_err(|err| {
+        error!(?err, "Unable to send response");
+        LdapError::Transport
+    })
+}
+
+async fn send_search_entry(
+    w: &ResponseTx,
+    msgid: i32,
+    entry: LdapSearchResultEntry,
+    ctrl: Vec<LdapControl>,
+) -> Result<(), LdapError> {
+    w.send(LdapMsg {
+        msgid,
+        op: LdapOp::SearchResultEntry(entry),
+        ctrl,
+    })
+    .await
+    .map_err(|err| {
+        error!(?err, "Unable to send response");
+        LdapError::Transport
+    })
+}
+
+async fn send_search_done(
+    w: &ResponseTx,
+    msgid: i32,
+    result: LdapResult,
+    ctrl: Vec<LdapControl>,
+) -> Result<(), LdapError> {
     w.send(LdapMsg {
         msgid,
         op: LdapOp::SearchResultDone(result),
@@ -372,18 +1262,32 @@ async fn search<W: AsyncWrite + Unpin>(
     .map_err(|err| {
         error!(?err, "Unable to send response");
         LdapError::Transport
-    })?;
+    })
+}
 
-    // Try and quiesce now.
-    app_state.cache.try_quiesce();
+fn limit_exceeded(code: LdapResultCode) -> LdapResult {
+    LdapResult {
+        code,
+        matcheddn: "".to_string(),
+        message: "".to_string(),
+        referral: vec![],
+    }
+}
 
-    // No state change
-    Ok(())
+/// End a search that the ldap server couldn't answer, after any entries that were sent.
+async fn send_search_error(w: &ResponseTx, msgid: i32) -> Result<(), LdapError> {
+    let result = LdapResult {
+        code: LdapResultCode::OperationsError,
+        matcheddn: "".to_string(),
+        message: "unable to search".to_string(),
+        referral: vec![],
+    };
+    send_search_done(w, msgid, result, Vec::with_capacity(0)).await
 }
 
 #[instrument(level = "info", skip_all)]
-async fn extop<W: AsyncWrite + Unpin>(
-    w: &mut FramedWrite<W, LdapCodec>,
+async fn extop(
+    w: &ResponseTx,
 
     ler: LdapExtendedRequest,
     msgid: i32,
@@ -427,6 +1331,585 @@ async fn extop<W: AsyncWrite + Unpin>(
     Ok(None)
 }
 
+#[instrument(level = "info", skip_all)]
+async fn starttls(w: &ResponseTx, msgid: i32, tls_active: bool) -> Result<bool, LdapError> {
+    // StartTLS can only be performed once per connection.
+    let (code, message) = if tls_active {
+        (LdapResultCode::OperationsError, "TLS is already active")
+    } else {
+        (LdapResultCode::Success, "")
+    };
+
+    w.send(LdapMsg {
+        msgid,
+        op: LdapOp::ExtendedResponse(LdapExtendedResponse {
+            res: LdapResult {
+                code,
+                matcheddn: "".to_string(),
+                message: message.to_string(),
+                referral: vec![],
+            },
+            name: Some(OID_STARTTLS.to_string()),
+            value: None,
+        }),
+        ctrl: vec![],
+    })
+    .await
+    .map_err(|err| {
+        error!(?err, "Unable to send response");
+        LdapError::Transport
+    })?;
+
+    Ok(!tls_active)
+}
+
+async fn confidentiality_required(
+    w: &ResponseTx,
+    msgid: i32,
+    request: &LdapOp,
+) -> Result<(), LdapError> {
+    let res = LdapResult {
+        code: LdapResultCode::ConfidentialityRequired,
+        matcheddn: "".to_string(),
+        message: "StartTLS is required".to_string(),
+        referral: vec![],
+    };
+
+    w.send(LdapMsg {
+        msgid,
+        op: result_response(request)(res),
+        ctrl: vec![],
+    })
+    .await
+    .map_err(|err| {
+        error!(?err, "Unable to send response");
+        LdapError::Transport
+    })
+}
+
+/// How to respond to a request with only a result.
+fn result_response(request: &LdapOp) -> fn(LdapResult) -> LdapOp {
+    match request {
+        LdapOp::BindRequest(_) => |res| {
+            LdapOp::BindResponse(LdapBindResponse {
+                res,
+                saslcreds: None,
+            })
+        },
+        LdapOp::CompareRequest(_) => LdapOp::CompareResult,
+        LdapOp::AddRequest(_) => LdapOp::AddResponse,
+        LdapOp::ModifyRequest(_) => LdapOp::ModifyResponse,
+        LdapOp::DelRequest(_) => LdapOp::DelResponse,
+        LdapOp::ModifyDNRequest(_) => LdapOp::ModifyDNResponse,
+        _ => LdapOp::SearchResultDone,
+    }
+}
+
+/// Why a client session stopped processing messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionEnd {
+    /// The client disconnected, or the session must be disconnected.
+    Disconnect,
+    /// The client requested StartTLS and has been told to proceed. The caller must
+    /// establish TLS on the underlying stream and then continue processing the session.
+    StartTls,
+}
+
+/// The state of a single client connection, which persists across a StartTLS upgrade
+/// of the underlying stream.
+pub struct ClientSession {
+    state: ClientState,
+    client_address: SocketAddr,
+    /// The address of the client as reported by a proxy, or else the address it
+    /// connected from.
+    client_ip: IpAddr,
+    app_state: Arc<AppState>,
+}
+
+impl ClientSession {
+    pub fn new(
+        client_address: SocketAddr,
+        reported_client_address: Option<SocketAddr>,
+        app_state: Arc<AppState>,
+    ) -> Self {
+        if let Some(reported_client_address) = reported_client_address {
+            info!(?reported_client_address, via = ?client_address, "new client");
+        } else {
+            info!(?client_address, "new client");
+        };
+
+        ClientSession {
+            // We always start unbound.
+            state: ClientState::Unbound,
+            client_address,
+            client_ip: reported_client_address.unwrap_or(client_address).ip(),
+            app_state,
+        }
+    }
+
+    /// Process messages from the client until it disconnects, or requests StartTLS.
+    /// `tls_active` indicates if the stream is already protected by TLS.
+    pub async fn process<W: AsyncWrite + Unpin, R: AsyncRead + Unpin>(
+        &mut self,
+        r: &mut FramedRead<R, LdapCodec>,
+        w: &mut FramedWrite<W, LdapCodec>,
+        tls_active: bool,
+    ) -> SessionEnd {
+        let end = self.process_inner(r, w, tls_active).await;
+        if end == SessionEnd::Disconnect {
+            info!("Disconnect for {}", self.client_address);
+        }
+        end
+    }
+
+    async fn process_inner<W: AsyncWrite + Unpin, R: AsyncRead + Unpin>(
+        &mut self,
+        r: &mut FramedRead<R, LdapCodec>,
+        w: &mut FramedWrite<W, LdapCodec>,
+        tls_active: bool,
+    ) -> SessionEnd {
+        let app_state = self.app_state.clone();
+        let tls_required = !tls_active && app_state.starttls_required;
+
+        let (resp_tx, mut resp_rx) = mpsc::channel(CLIENT_QUEUE_LEN);
+        let mut operations = Operations::default();
+
+        let end = loop {
+            // Send responses as they are ready, and only then read the next request.
+            let protomsg = tokio::select! {
+                biased;
+                Some(resp_msg) = resp_rx.recv() => {
+                    if send_response(w, resp_msg).await.is_err() {
+                        break SessionEnd::Disconnect;
+                    }
+                    continue;
+                }
+                Some(res) = operations.tasks.join_next_with_id() => {
+                    if operations.complete(res).is_err() {
+                        break SessionEnd::Disconnect;
+                    }
+                    continue;
+                }
+                res = timeout(LDAP_CLIENT_IO_TIMEOUT, r.next()) => match res {
+                    Ok(Some(Ok(protomsg))) => protomsg,
+                    // The client may be waiting on an operation that is slow to complete.
+                    Err(_) if !operations.is_empty() => continue,
+                    _ => break SessionEnd::Disconnect,
+                }
+            };
+
+            let next_state = match (&self.state, protomsg) {
+                // StartTLS may be requested in any state.
+                (
+                    _,
+                    LdapMsg {
+                        msgid,
+                        op: LdapOp::ExtendedRequest(ler),
+                        ctrl: _,
+                    },
+                ) if ler.name == OID_STARTTLS => {
+                    // Responses to the outstanding operations must be sent before TLS is
+                    // established.
+                    if drain(w, &mut resp_rx, &mut operations).await.is_err() {
+                        break SessionEnd::Disconnect;
+                    }
+                    match starttls(&resp_tx, msgid, tls_active).await {
+                        Ok(true) => {
+                            if flush(w, &mut resp_rx).await.is_err() {
+                                break SessionEnd::Disconnect;
+                            }
+                            return SessionEnd::StartTls;
+                        }
+                        Ok(false) => None,
+                        Err(_) => break SessionEnd::Disconnect,
+                    }
+                }
+                // Until TLS is active binds and searches are refused if TLS is required.
+                (
+                    _,
+                    LdapMsg {
+                        msgid,
+                        op:
+                            op @ (LdapOp::BindRequest(_)
+                            | LdapOp::SearchRequest(_)
+                            | LdapOp::CompareRequest(_)
+                            | LdapOp::AddRequest(_)
+                            | LdapOp::ModifyRequest(_)
+                            | LdapOp::DelRequest(_)
+                            | LdapOp::ModifyDNRequest(_)),
+                        ctrl: _,
+                    },
+                ) if tls_required => {
+                    warn!(
+                        "Refusing operation from {} until StartTLS",
+                        self.client_address
+                    );
+                    match confidentiality_required(&resp_tx, msgid, &op).await {
+                        Ok(()) => None,
+                        Err(_) => break SessionEnd::Disconnect,
+                    }
+                }
+                // Abandon an outstanding operation. There is no response to this.
+                (
+                    _,
+                    LdapMsg {
+                        msgid: _,
+                        op: LdapOp::AbandonRequest(abandon_msgid),
+                        ctrl: _,
+                    },
+                ) => {
+                    operations.abandon(abandon_msgid);
+                    None
+                }
+                // Doesn't matter what state we are in, any bind will trigger this process.
+                (
+                    _,
+                    LdapMsg {
+                        msgid,
+                        op: LdapOp::BindRequest(lbr),
+                        ctrl,
+                    },
+                ) => {
+                    // A bind changes the identity that operations are performed as, so the
+                    // outstanding operations must complete first.
+                    if drain(w, &mut resp_rx, &mut operations).await.is_err() {
+                        break SessionEnd::Disconnect;
+                    }
+                    match bind(&resp_tx, &app_state, Some(self.client_ip), lbr, msgid, ctrl).await {
+                        Ok(ns) => ns,
+                        Err(_) => break SessionEnd::Disconnect,
+                    }
+                }
+                // Unbinds are always actioned.
+                (
+                    _,
+                    LdapMsg {
+                        msgid: _,
+                        op: LdapOp::UnbindRequest,
+                        ctrl: _,
+                    },
+                ) => {
+                    unbind().await;
+                    break SessionEnd::Disconnect;
+                }
+
+                // Unbound handler
+                (
+                    ClientState::Unbound,
+                    LdapMsg {
+                        msgid,
+                        op:
+                            op @ (LdapOp::SearchRequest(_)
+                            | LdapOp::CompareRequest(_)
+                            | LdapOp::AddRequest(_)
+                            | LdapOp::ModifyRequest(_)
+                            | LdapOp::DelRequest(_)
+                            | LdapOp::ModifyDNRequest(_)),
+                        ctrl,
+                    },
+                ) => {
+                    // We have to trigger a bind first in case we have a mapping.
+                    let lbr = LdapBindRequest {
+                        dn: "".to_string(),
+                        cred: LdapBindCred::Simple("".to_string()),
+                    };
+
+                    let next_state = match bind(
+                        &resp_tx,
+                        &app_state,
+                        Some(self.client_ip),
+                        lbr,
+                        0,
+                        Vec::default(),
+                    )
+                    .await
+                    {
+                        Ok(ns) => ns,
+                        Err(_) => break SessionEnd::Disconnect,
+                    };
+
+                    match &next_state {
+                        Some(ClientState::Unbound) | None => {
+                            error!("Invalid state, bind did not return an authenticated state!");
+                            break SessionEnd::Disconnect;
+                        }
+                        Some(authenticated) => {
+                            if operations
+                                .spawn(&resp_tx, &app_state, authenticated, op, msgid, ctrl)
+                                .is_err()
+                            {
+                                break SessionEnd::Disconnect;
+                            }
+                        }
+                    }
+
+                    next_state
+                }
+
+                // Authenticated message handler.
+                //  - Search
+                //  - Compare
+                //  - Add, Modify, Delete and ModifyDN
+                (
+                    authenticated @ ClientState::Authenticated { .. },
+                    LdapMsg {
+                        msgid,
+                        op:
+                            op @ (LdapOp::SearchRequest(_)
+                            | LdapOp::CompareRequest(_)
+                            | LdapOp::AddRequest(_)
+                            | LdapOp::ModifyRequest(_)
+                            | LdapOp::DelRequest(_)
+                            | LdapOp::ModifyDNRequest(_)),
+                        ctrl,
+                    },
+                ) => {
+                    if operations
+                        .spawn(&resp_tx, &app_state, authenticated, op, msgid, ctrl)
+                        .is_err()
+                    {
+                        break SessionEnd::Disconnect;
+                    }
+                    None
+                }
+                // Extended Requests - Generally whoami.
+                (
+                    ClientState::Authenticated {
+                        upstream_dn: _,
+                        display_dn,
+                        config: _,
+                        client: _,
+                        bind_pool: _,
+                    },
+                    LdapMsg {
+                        msgid,
+                        op: LdapOp::ExtendedRequest(ler),
+                        ctrl: _,
+                    },
+                ) => match extop(&resp_tx, ler, msgid, display_dn).await {
+                    Ok(ns) => ns,
+                    Err(_) => break SessionEnd::Disconnect,
+                },
+                // Unknown message handler.
+                (_, msg) => {
+                    debug!(?msg, "Invalid message state, triggering disconnection");
+                    // Return a disconnect.
+                    break SessionEnd::Disconnect;
+                }
+            };
+
+            if let Some(next_state) = next_state {
+                // Update the client state, releasing any former state.
+                std::mem::replace(&mut self.state, next_state).release();
+            }
+
+            // Send any responses that are already queued.
+            if flush(w, &mut resp_rx).await.is_err() {
+                break SessionEnd::Disconnect;
+            }
+        };
+
+        // Stop the outstanding operations before the connection is released.
+        operations.tasks.shutdown().await;
+        std::mem::replace(&mut self.state, ClientState::Unbound).release();
+        end
+    }
+}
+
+/// The operations that are in progress on a client session.
+#[derive(Default)]
+struct Operations {
+    tasks: JoinSet<Result<(), LdapError>>,
+    by_msgid: HashMap<i32, Operation>,
+}
+
+struct Operation {
+    abort_handle: AbortHandle,
+    /// A write may have been made by the ldap server before it is abandoned, and must
+    /// complete so that the cached results it changed are invalidated.
+    abandonable: bool,
+}
+
+impl Operations {
+    fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    /// Start an operation that is forwarded to the ldap server.
+    fn spawn(
+        &mut self,
+        resp_tx: &ResponseTx,
+        app_state: &Arc<AppState>,
+        state: &ClientState,
+        op: LdapOp,
+        msgid: i32,
+        ctrl: Vec<LdapControl>,
+    ) -> Result<(), ()> {
+        let ClientState::Authenticated {
+            upstream_dn,
+            display_dn,
+            config,
+            client,
+            bind_pool: _,
+        } = state
+        else {
+            error!("Invalid state, operation requires an authenticated state!");
+            return Err(());
+        };
+
+        // A msgid must not be reused until the operation it refers to has completed.
+        if self.by_msgid.contains_key(&msgid) {
+            error!(%msgid, "msgid is already in use by an outstanding operation");
+            return Err(());
+        }
+
+        let abandonable = !matches!(
+            op,
+            LdapOp::AddRequest(_)
+                | LdapOp::ModifyRequest(_)
+                | LdapOp::DelRequest(_)
+                | LdapOp::ModifyDNRequest(_)
+        );
+
+        let resp_tx = resp_tx.clone();
+        let app_state = app_state.clone();
+        let upstream_dn = upstream_dn.clone();
+        let display_dn = display_dn.clone();
+        let config = config.clone();
+        let client = client.clone();
+
+        let abort_handle = self.tasks.spawn(
+            async move {
+                match op {
+                    LdapOp::SearchRequest(sr) => {
+                        let search_req = SearchRequest {
+                            sr,
+                            msgid,
+                            ctrl,
+                            upstream_dn: &upstream_dn,
+                            display_dn: &display_dn,
+                            config: &config,
+                            client: &client,
+                            refresh: false,
+                        };
+                        search(&resp_tx, &app_state, search_req).await.map(|_| ())
+                    }
+                    LdapOp::CompareRequest(cr) => {
+                        let compare_req = CompareRequest {
+                            cr,
+                            msgid,
+                            ctrl,
+                            upstream_dn: &upstream_dn,
+                            display_dn: &display_dn,
+                            config: &config,
+                            client: &client,
+                        };
+                        compare(&resp_tx, &app_state, compare_req).await
+                    }
+                    op @ (LdapOp::AddRequest(_)
+                    | LdapOp::ModifyRequest(_)
+                    | LdapOp::DelRequest(_)
+                    | LdapOp::ModifyDNRequest(_)) => {
+                        let write_req = WriteRequest {
+                            op,
+                            msgid,
+                            ctrl,
+                            display_dn: &display_dn,
+                            config: &config,
+                            client: &client,
+                        };
+                        write(&resp_tx, &app_state, write_req).await
+                    }
+                    op => {
+                        error!(?op, "Invalid operation, unable to forward to ldap server");
+                        Err(LdapError::InvalidProtocolState)
+                    }
+                }
+            }
+            .in_current_span(),
+        );
+
+        self.by_msgid.insert(
+            msgid,
+            Operation {
+                abort_handle,
+                abandonable,
+            },
+        );
+        Ok(())
+    }
+
+    fn abandon(&mut self, msgid: i32) {
+        match self.by_msgid.get(&msgid) {
+            Some(operation) if operation.abandonable => {
+                debug!(%msgid, "abandoning operation");
+                operation.abort_handle.abort();
+                self.by_msgid.remove(&msgid);
+            }
+            Some(_) => debug!(%msgid, "writes are not abandoned"),
+            None => debug!(%msgid, "no outstanding operation to abandon"),
+        }
+    }
+
+    fn complete(
+        &mut self,
+        res: Result<(task::Id, Result<(), LdapError>), JoinError>,
+    ) -> Result<(), ()> {
+        let id = match res {
+            Ok((id, Ok(()))) => id,
+            Ok((_, Err(err))) => {
+                debug!(?err, "operation failed");
+                return Err(());
+            }
+            Err(err) if err.is_cancelled() => err.id(),
+            Err(err) => {
+                error!(?err, "operation failed unexpectedly");
+                return Err(());
+            }
+        };
+
+        self.by_msgid
+            .retain(|_, operation| operation.abort_handle.id() != id);
+        Ok(())
+    }
+}
+
+async fn send_response<W: AsyncWrite + Unpin>(
+    w: &mut FramedWrite<W, LdapCodec>,
+    resp_msg: LdapMsg,
+) -> Result<(), ()> {
+    w.send(resp_msg).await.map_err(|err| {
+        error!(?err, "Unable to send response");
+    })
+}
+
+/// Send the responses that are queued, without waiting for any more.
+async fn flush<W: AsyncWrite + Unpin>(
+    w: &mut FramedWrite<W, LdapCodec>,
+    resp_rx: &mut mpsc::Receiver<LdapMsg>,
+) -> Result<(), ()> {
+    while let Ok(resp_msg) = resp_rx.try_recv() {
+        send_response(w, resp_msg).await?;
+    }
+    Ok(())
+}
+
+/// Wait for the outstanding operations to complete, sending their responses.
+async fn drain<W: AsyncWrite + Unpin>(
+    w: &mut FramedWrite<W, LdapCodec>,
+    resp_rx: &mut mpsc::Receiver<LdapMsg>,
+    operations: &mut Operations,
+) -> Result<(), ()> {
+    while !operations.is_empty() {
+        tokio::select! {
+            Some(resp_msg) = resp_rx.recv() => send_response(w, resp_msg).await?,
+            Some(res) = operations.tasks.join_next_with_id() => operations.complete(res)?,
+            else => break,
+        }
+    }
+    flush(w, resp_rx).await
+}
+
 pub async fn client_process<W: AsyncWrite + Unpin, R: AsyncRead + Unpin>(
     mut r: FramedRead<R, LdapCodec>,
     mut w: FramedWrite<W, LdapCodec>,
@@ -434,190 +1917,331 @@ pub async fn client_process<W: AsyncWrite + Unpin, R: AsyncRead + Unpin>(
     reported_client_address: Option<SocketAddr>,
     app_state: Arc<AppState>,
 ) {
-    if let Some(reported_client_address) = reported_client_address {
-        info!(?reported_client_address, via = ?client_address, "new client");
-    } else {
-        info!(?client_address, "new client");
+    let mut session = ClientSession::new(client_address, reported_client_address, app_state);
+
+    // The stream is already protected by TLS, so StartTLS can't be requested.
+    let _ = session.process(&mut r, &mut w, true).await;
+}
+
+/// Process a client that connected without TLS, establishing TLS on the stream when
+/// the client requests StartTLS.
+pub async fn starttls_client_process<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    client_address: SocketAddr,
+    reported_client_address: Option<SocketAddr>,
+    tls_acceptor: TlsAcceptor,
+    app_state: Arc<AppState>,
+) {
+    let max_incoming_ber_size = app_state.max_incoming_ber_size;
+
+    let (r, w) = tokio::io::split(stream);
+    let mut r = FramedRead::new(r, LdapCodec::new(max_incoming_ber_size, None));
+    let mut w = FramedWrite::new(w, LdapCodec::new(max_incoming_ber_size, None));
+
+    let mut session = ClientSession::new(client_address, reported_client_address, app_state);
+
+    match session.process(&mut r, &mut w, false).await {
+        SessionEnd::Disconnect => return,
+        SessionEnd::StartTls => {}
+    }
+
+    // The client must wait for the StartTLS response before it begins the handshake,
+    // so anything already buffered is a protocol violation.
+    if !r.read_buffer().is_empty() {
+        error!("Client sent data before the StartTLS handshake, disconnecting");
+        return;
+    }
+
+    let stream = r.into_inner().unsplit(w.into_inner());
+
+    let tlsstream = match timeout(LDAP_CLIENT_CONN_TIMEOUT, tls_acceptor.accept(stream)).await {
+        Ok(Ok(ta)) => ta,
+        Ok(Err(err)) => {
+            error!(?err, "LDAP StartTLS setup error");
+            return;
+        }
+        Err(_) => {
+            error!("LDAP StartTLS timeout error");
+            return;
+        }
     };
 
-    // We always start unbound.
-    let mut state = ClientState::Unbound;
+    debug!("StartTLS established");
+
+    let (r, w) = tokio::io::split(tlsstream);
+    let mut r = FramedRead::new(r, LdapCodec::new(max_incoming_ber_size, None));
+    let mut w = FramedWrite::new(w, LdapCodec::new(max_incoming_ber_size, None));
+
+    let _ = session.process(&mut r, &mut w, true).await;
+}
+
+#[derive(Debug, Clone)]
+pub enum LdapError {
+    TlsError,
+    ConnectError,
+    Transport,
+    InvalidProtocolState,
+}
+
+/// The upstream ldap server, and how the connection to it is secured.
+#[derive(Debug, Clone)]
+pub enum LdapUpstream {
+    /// ldaps:// - TLS is established as soon as the connection is made.
+    Ldaps {
+        addrs: Vec<SocketAddr>,
+        tls_hostname: ServerName<'static>,
+    },
+    /// ldap:// - StartTLS is performed before any other operation.
+    StartTls {
+        addrs: Vec<SocketAddr>,
+        tls_hostname: ServerName<'static>,
+    },
+    /// ldap:// without StartTLS. This is insecure, and only intended for local development.
+    Plaintext { addrs: Vec<SocketAddr> },
+    /// ldapi:// - a unix socket on the local machine.
+    Ldapi { path: PathBuf },
+}
+
+enum UpstreamStream {
+    Tls(Box<TlsStream<TcpStream>>),
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl AsyncRead for UpstreamStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Tls(s) => Pin::new(s).poll_read(cx, buf),
+            UpstreamStream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            UpstreamStream::Unix(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for UpstreamStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            UpstreamStream::Tls(s) => Pin::new(s).poll_write(cx, buf),
+            UpstreamStream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            UpstreamStream::Unix(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Tls(s) => Pin::new(s).poll_flush(cx),
+            UpstreamStream::Tcp(s) => Pin::new(s).poll_flush(cx),
+            UpstreamStream::Unix(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Tls(s) => Pin::new(s).poll_shutdown(cx),
+            UpstreamStream::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            UpstreamStream::Unix(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+// Responses are routed to the operation that is waiting for them by their msgid.
+type Routes = HashMap<i32, mpsc::Sender<LdapMsg>>;
+
+// The number of responses that may be queued for an operation before reading from the
+// ldap server pauses.
+const UPSTREAM_QUEUE_LEN: usize = 128;
+
+struct ClientShared {
+    routes: Mutex<Routes>,
+    failed: AtomicBool,
+}
 
-    // Start to wait for incoming packets
-    while let Ok(Some(Ok(protomsg))) = timeout(LDAP_CLIENT_IO_TIMEOUT, r.next()).await {
-        let next_state = match (&mut state, protomsg) {
-            // Doesn't matter what state we are in, any bind will trigger this process.
-            (
-                _,
-                LdapMsg {
-                    msgid,
-                    op: LdapOp::BindRequest(lbr),
-                    ctrl,
-                },
-            ) => match bind(&mut w, &app_state, lbr, msgid, ctrl).await {
-                Ok(ns) => ns,
-                Err(_) => break,
-            },
-            // Unbinds are always actioned.
-            (
-                _,
-                LdapMsg {
-                    msgid: _,
-                    op: LdapOp::UnbindRequest,
-                    ctrl: _,
-                },
-            ) => {
-                unbind().await;
-                break;
-            }
+impl ClientShared {
+    fn fail(&self) {
+        self.failed.store(true, Ordering::Relaxed);
+        // Dropping the routes wakes any waiting operations.
+        if let Ok(mut routes) = self.routes.lock() {
+            routes.clear();
+        }
+    }
+}
 
-            // Unbound handler
-            (
-                ClientState::Unbound,
-                LdapMsg {
-                    msgid,
-                    op: LdapOp::SearchRequest(sr),
-                    ctrl,
-                },
-            ) => {
-                // We have to trigger a bind first in case we have a mapping.
-                let lbr = LdapBindRequest {
-                    dn: "".to_string(),
-                    cred: LdapBindCred::Simple("".to_string()),
-                };
+/// A connection to the remote ldap server. Multiple operations may be outstanding on
+/// the connection at the same time, and their responses are returned to each operation
+/// as they arrive.
+pub struct BasicLdapClient {
+    tx: mpsc::UnboundedSender<LdapMsg>,
+    shared: Arc<ClientShared>,
+    msg_counter: AtomicI32,
+    reader: AbortHandle,
+}
 
-                let mut next_state = match bind(&mut w, &app_state, lbr, 0, Vec::default()).await {
-                    Ok(ns) => ns,
-                    Err(_) => break,
-                };
+impl Drop for BasicLdapClient {
+    fn drop(&mut self) {
+        // The writer stops once the sender is dropped, but the reader must be stopped
+        // so that the connection is closed.
+        self.reader.abort();
+    }
+}
 
-                match &mut next_state {
-                    Some(ClientState::Unbound) | None => {
-                        error!("Invalid state, bind did not return an authenticated state!");
-                        break;
-                    }
-                    Some(ClientState::Authenticated {
-                        dn,
-                        display_dn,
-                        config,
-                        ref mut client,
-                    }) => {
-                        let search_req = SearchRequest {
-                            sr,
-                            msgid,
-                            ctrl,
-                            dn,
-                            display_dn,
-                            config,
-                            client,
-                        };
-                        match search(&mut w, &app_state, search_req).await {
-                            Ok(()) => {}
-                            Err(_) => break,
-                        }
-                    }
-                }
+/// An operation in progress. If it's dropped before it completes, such as when the
+/// client abandons it, the ldap server is asked to abandon it too.
+struct PendingOp<'a> {
+    client: &'a BasicLdapClient,
+    msgid: i32,
+    rx: mpsc::Receiver<LdapMsg>,
+    complete: bool,
+}
 
-                next_state
+impl PendingOp<'_> {
+    async fn next(&mut self) -> Result<LdapMsg, LdapError> {
+        match timeout(LDAP_CLIENT_IO_TIMEOUT, self.rx.recv()).await {
+            Ok(Some(msg)) => Ok(msg),
+            Ok(None) => {
+                error!("connection closed");
+                Err(LdapError::Transport)
+            }
+            Err(_) => {
+                error!("connection timeout");
+                Err(LdapError::Transport)
             }
+        }
+    }
 
-            // Authenticated message handler.
-            //  - Search
-            (
-                ClientState::Authenticated {
-                    dn,
-                    display_dn,
-                    config,
-                    ref mut client,
-                },
-                LdapMsg {
-                    msgid,
-                    op: LdapOp::SearchRequest(sr),
-                    ctrl,
-                },
-            ) => {
-                let search_req = SearchRequest {
-                    sr,
-                    msgid,
-                    ctrl,
-                    dn,
-                    display_dn,
-                    config,
-                    client,
-                };
+    fn complete(&mut self) {
+        self.complete = true;
+    }
+}
 
-                match search(&mut w, &app_state, search_req).await {
-                    Ok(()) => None,
-                    Err(_) => break,
-                }
+impl Drop for PendingOp<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut routes) = self.client.shared.routes.lock() {
+            routes.remove(&self.msgid);
+        }
+
+        if !self.complete && !self.client.is_failed() {
+            debug!(msgid = %self.msgid, "abandoning operation");
+            let msgid = self.client.next_msgid();
+            let _ = self.client.tx.send(LdapMsg {
+                msgid,
+                op: LdapOp::AbandonRequest(self.msgid),
+                ctrl: vec![],
+            });
+        }
+    }
+}
+
+async fn upstream_writer(
+    mut w: FramedWrite<CW, LdapCodec>,
+    mut rx: mpsc::UnboundedReceiver<LdapMsg>,
+    shared: Arc<ClientShared>,
+) {
+    while let Some(msg) = rx.recv().await {
+        match timeout(LDAP_CLIENT_IO_TIMEOUT, w.send(msg)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(err)) => {
+                error!(?err, "unable to transmit to ldap server");
+                shared.fail();
+                break;
             }
-            // Extended Requests - Generally whoami.
-            (
-                ClientState::Authenticated {
-                    dn: _,
-                    display_dn,
-                    config: _,
-                    client: _,
-                },
-                LdapMsg {
-                    msgid,
-                    op: LdapOp::ExtendedRequest(ler),
-                    ctrl: _,
-                },
-            ) => match extop(&mut w, ler, msgid, display_dn).await {
-                Ok(ns) => ns,
-                Err(_) => break,
-            },
-            // Unknown message handler.
-            (_, msg) => {
-                debug!(?msg, "Invalid message state, triggering disconnection");
-                // Return a disconnect.
+            Err(_) => {
+                error!("timeout during transmit to ldap server");
+                shared.fail();
                 break;
             }
         };
+    }
+}
 
-        if let Some(next_state) = next_state {
-            // Update the client state, dropping any former state.
-            state = next_state;
+async fn upstream_reader(mut r: FramedRead<CR, LdapCodec>, shared: Arc<ClientShared>) {
+    loop {
+        match r.next().await {
+            Some(Ok(msg)) => {
+                let route = shared
+                    .routes
+                    .lock()
+                    .ok()
+                    .and_then(|routes| routes.get(&msg.msgid).cloned());
+
+                if let Some(route) = route {
+                    // If the operation was dropped meanwhile, the response is discarded.
+                    let _ = route.send(msg).await;
+                } else if msg.msgid == 0 {
+                    // An unsolicited notification, which is always a notice of disconnection.
+                    warn!(?msg, "ldap server has closed the connection");
+                    break;
+                } else {
+                    trace!(?msg, "discarding response to abandoned operation");
+                }
+            }
+            Some(Err(e)) => {
+                error!(?e, "unable to receive from ldap server");
+                break;
+            }
+            None => {
+                debug!("connection closed");
+                break;
+            }
         }
     }
-    info!("Disconnect for {}", client_address);
+    shared.fail();
 }
 
-#[derive(Debug, Clone)]
-pub enum LdapError {
-    TlsError,
-    ConnectError,
-    Transport,
-    InvalidProtocolState,
-}
+impl BasicLdapClient {
+    fn next_msgid(&self) -> i32 {
+        self.msg_counter.fetch_add(1, Ordering::Relaxed) + 1
+    }
 
-pub struct BasicLdapClient {
-    r: FramedRead<CR, LdapCodec>,
-    w: FramedWrite<CW, LdapCodec>,
-    msg_counter: i32,
-}
+    /// Send a request, returning the operation to receive the responses from.
+    fn start(&self, op: LdapOp, ctrl: Vec<LdapControl>) -> Result<PendingOp<'_>, LdapError> {
+        if self.is_failed() {
+            return Err(LdapError::Transport);
+        }
 
-impl BasicLdapClient {
-    fn next_msgid(&mut self) -> i32 {
-        self.msg_counter += 1;
-        self.msg_counter
+        let msgid = self.next_msgid();
+        let (route_tx, rx) = mpsc::channel(UPSTREAM_QUEUE_LEN);
+
+        self.shared
+            .routes
+            .lock()
+            .map_err(|_| LdapError::Transport)?
+            .insert(msgid, route_tx);
+
+        let pending = PendingOp {
+            client: self,
+            msgid,
+            rx,
+            complete: false,
+        };
+
+        self.tx.send(LdapMsg { msgid, op, ctrl }).map_err(|_| {
+            error!("unable to transmit to ldap server");
+            LdapError::Transport
+        })?;
+
+        Ok(pending)
     }
 
-    pub async fn build(
+    async fn connect_tcp(
         addrs: &[SocketAddr],
-        hostname: &ServerName<'static>,
-        tls_connector: &TlsConnector,
-        max_ber_size: Option<usize>,
-    ) -> Result<Self, LdapError> {
+        connect_timeout: Duration,
+    ) -> Result<TcpStream, LdapError> {
         let mut aiter = addrs.iter();
 
-        let tcpstream = loop {
+        loop {
             if let Some(addr) = aiter.next() {
-                match timeout(LDAP_CLIENT_CONN_TIMEOUT, TcpStream::connect(addr)).await {
+                match timeout(connect_timeout, TcpStream::connect(addr)).await {
                     Ok(Ok(t)) => {
                         trace!(?addr, "connection established");
-                        break t;
+                        break Ok(t);
                     }
                     Ok(Err(err)) => {
                         trace!(?addr, ?err, "error");
@@ -629,54 +2253,53 @@ impl BasicLdapClient {
                     }
                 }
             } else {
-                return Err(LdapError::ConnectError);
+                break Err(LdapError::ConnectError);
             }
-        };
+        }
+    }
 
-        let tlsstream = match timeout(
-            LDAP_CLIENT_CONN_TIMEOUT,
+    async fn connect_tls(
+        tcpstream: TcpStream,
+        hostname: &ServerName<'static>,
+        tls_connector: &TlsConnector,
+        connect_timeout: Duration,
+    ) -> Result<TlsStream<TcpStream>, LdapError> {
+        match timeout(
+            connect_timeout,
             tls_connector.connect(hostname.clone(), tcpstream),
         )
         .await
         {
-            Ok(Ok(ts)) => ts,
+            Ok(Ok(ts)) => Ok(ts),
             Ok(err) => {
                 error!(?err, "Unable to establish TLS");
-                return Err(LdapError::TlsError);
+                Err(LdapError::TlsError)
             }
             Err(_) => {
                 error!("Timeout establishish TLS");
-                return Err(LdapError::TlsError);
+                Err(LdapError::TlsError)
             }
-        };
-
-        let (r, w) = tokio::io::split(tlsstream);
-
-        let w = FramedWrite::new(w, LdapCodec::new(max_ber_size, None));
-        let r = FramedRead::new(r, LdapCodec::new(max_ber_size, None));
-
-        info!("Connected to remote ldap server");
-        Ok(BasicLdapClient {
-            r,
-            w,
-            msg_counter: 0,
-        })
+        }
     }
 
-    pub async fn bind(
-        &mut self,
-        lbr: LdapBindRequest,
-        ctrl: Vec<LdapControl>,
-    ) -> Result<(LdapBindResponse, Vec<LdapControl>), LdapError> {
-        let ck_msgid = self.next_msgid();
+    /// Request StartTLS on a cleartext connection, returning the connection once the
+    /// server has agreed to proceed with the TLS handshake.
+    async fn starttls(
+        tcpstream: TcpStream,
+        max_ber_size: Option<usize>,
+    ) -> Result<TcpStream, LdapError> {
+        let mut framed = Framed::new(tcpstream, LdapCodec::new(max_ber_size, None));
 
         let msg = LdapMsg {
-            msgid: ck_msgid,
-            op: LdapOp::BindRequest(lbr),
-            ctrl,
+            msgid: STARTTLS_MSGID,
+            op: LdapOp::ExtendedRequest(LdapExtendedRequest {
+                name: OID_STARTTLS.to_string(),
+                value: None,
+            }),
+            ctrl: vec![],
         };
 
-        match timeout(LDAP_CLIENT_IO_TIMEOUT, self.w.send(msg)).await {
+        match timeout(LDAP_CLIENT_IO_TIMEOUT, framed.send(msg)).await {
             Ok(Ok(_)) => {}
             Ok(Err(err)) => {
                 error!(?err, "unable to transmit to ldap server");
@@ -688,114 +2311,307 @@ impl BasicLdapClient {
             }
         };
 
-        match timeout(LDAP_CLIENT_IO_TIMEOUT, self.r.next()).await {
+        match timeout(LDAP_CLIENT_IO_TIMEOUT, framed.next()).await {
             Ok(Some(Ok(LdapMsg {
-                msgid,
-                op: LdapOp::BindResponse(bind_resp),
-                ctrl,
+                msgid: STARTTLS_MSGID,
+                op: LdapOp::ExtendedResponse(ext_resp),
+                ctrl: _,
             }))) => {
-                if msgid == ck_msgid {
-                    Ok((bind_resp, ctrl))
-                } else {
-                    error!("invalid msgid, sequence error.");
-                    Err(LdapError::InvalidProtocolState)
+                if ext_resp.res.code != LdapResultCode::Success {
+                    error!(res = ?ext_resp.res, "ldap server refused StartTLS");
+                    return Err(LdapError::TlsError);
                 }
             }
             Ok(Some(Ok(msg))) => {
                 trace!(?msg);
-                Err(LdapError::InvalidProtocolState)
+                return Err(LdapError::InvalidProtocolState);
             }
             Ok(Some(Err(e))) => {
                 error!(?e, "unable to receive from ldap server");
-                Err(LdapError::Transport)
+                return Err(LdapError::Transport);
             }
             Ok(None) => {
                 error!("connection closed");
-                Err(LdapError::Transport)
+                return Err(LdapError::Transport);
             }
             Err(_) => {
                 error!("connection timeout");
-                Err(LdapError::Transport)
+                return Err(LdapError::Transport);
             }
+        };
+
+        let parts = framed.into_parts();
+        if !parts.read_buf.is_empty() {
+            error!("ldap server sent data before the StartTLS handshake");
+            return Err(LdapError::InvalidProtocolState);
         }
+
+        Ok(parts.io)
     }
 
-    pub async fn search(
-        &mut self,
-        sr: LdapSearchRequest,
+    /// Connect to the ldap server. Each address, and the TLS handshake, must complete
+    /// within the connect timeout.
+    pub async fn build(
+        upstream: &LdapUpstream,
+        tls_connector: &TlsConnector,
+        max_ber_size: Option<usize>,
+        connect_timeout: Duration,
+    ) -> Result<Self, LdapError> {
+        let (stream, msg_counter) = match upstream {
+            LdapUpstream::Ldaps {
+                addrs,
+                tls_hostname,
+            } => {
+                let tcpstream = Self::connect_tcp(addrs, connect_timeout).await?;
+                let tlsstream =
+                    Self::connect_tls(tcpstream, tls_hostname, tls_connector, connect_timeout)
+                        .await?;
+                (UpstreamStream::Tls(Box::new(tlsstream)), 0)
+            }
+            LdapUpstream::StartTls {
+                addrs,
+                tls_hostname,
+            } => {
+                let tcpstream = Self::connect_tcp(addrs, connect_timeout).await?;
+                let tcpstream = Self::starttls(tcpstream, max_ber_size).await?;
+                let tlsstream =
+                    Self::connect_tls(tcpstream, tls_hostname, tls_connector, connect_timeout)
+                        .await?;
+                // The StartTLS request consumed the first msgid.
+                (UpstreamStream::Tls(Box::new(tlsstream)), STARTTLS_MSGID)
+            }
+            LdapUpstream::Plaintext { addrs } => {
+                let tcpstream = Self::connect_tcp(addrs, connect_timeout).await?;
+                debug!("Connected to remote ldap server without TLS");
+                (UpstreamStream::Tcp(tcpstream), 0)
+            }
+            LdapUpstream::Ldapi { path } => {
+                match timeout(connect_timeout, UnixStream::connect(path)).await {
+                    Ok(Ok(unixstream)) => {
+                        trace!(?path, "connection established");
+                        (UpstreamStream::Unix(unixstream), 0)
+                    }
+                    Ok(Err(err)) => {
+                        error!(?path, ?err, "Unable to connect to ldapi socket");
+                        return Err(LdapError::ConnectError);
+                    }
+                    Err(_) => {
+                        warn!(?path, "timeout");
+                        return Err(LdapError::ConnectError);
+                    }
+                }
+            }
+        };
+
+        let (r, w) = tokio::io::split(stream);
+
+        let w = FramedWrite::new(w, LdapCodec::new(max_ber_size, None));
+        let r = FramedRead::new(r, LdapCodec::new(max_ber_size, None));
+
+        let shared = Arc::new(ClientShared {
+            routes: Mutex::new(HashMap::new()),
+            failed: AtomicBool::new(false),
+        });
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(upstream_writer(w, rx, shared.clone()));
+        let reader = tokio::spawn(upstream_reader(r, shared.clone())).abort_handle();
+
+        info!("Connected to remote ldap server");
+        Ok(BasicLdapClient {
+            tx,
+            shared,
+            msg_counter: AtomicI32::new(msg_counter),
+            reader,
+        })
+    }
+
+    /// A connection that has already failed, on which every operation fails.
+    fn disconnected() -> Self {
+        let (tx, _) = mpsc::unbounded_channel();
+        BasicLdapClient {
+            tx,
+            shared: Arc::new(ClientShared {
+                routes: Mutex::new(HashMap::new()),
+                failed: AtomicBool::new(true),
+            }),
+            msg_counter: AtomicI32::new(0),
+            reader: tokio::spawn(async {}).abort_handle(),
+        }
+    }
+
+    /// True if an operation on this connection has failed, leaving it in an unknown
+    /// state. Such a connection must not be reused.
+    pub fn is_failed(&self) -> bool {
+        self.shared.failed.load(Ordering::Relaxed)
+    }
+
+    pub async fn bind(
+        &self,
+        lbr: LdapBindRequest,
         ctrl: Vec<LdapControl>,
-    ) -> Result<
-        (
-            Vec<(LdapSearchResultEntry, Vec<LdapControl>)>,
-            LdapResult,
-            Vec<LdapControl>,
-        ),
-        LdapError,
-    > {
-        let ck_msgid = self.next_msgid();
+    ) -> Result<(LdapBindResponse, Vec<LdapControl>), LdapError> {
+        let res = self.bind_inner(lbr, ctrl).await;
+        if res.is_err() {
+            self.shared.fail();
+        }
+        res
+    }
 
-        let msg = LdapMsg {
-            msgid: ck_msgid,
-            op: LdapOp::SearchRequest(sr),
-            ctrl,
-        };
+    async fn bind_inner(
+        &self,
+        lbr: LdapBindRequest,
+        ctrl: Vec<LdapControl>,
+    ) -> Result<(LdapBindResponse, Vec<LdapControl>), LdapError> {
+        let mut op = self.start(LdapOp::BindRequest(lbr), ctrl)?;
 
-        match timeout(LDAP_CLIENT_IO_TIMEOUT, self.w.send(msg)).await {
-            Ok(Ok(_)) => {}
-            Ok(Err(err)) => {
-                error!(?err, "unable to transmit to ldap server");
-                return Err(LdapError::Transport);
+        match op.next().await? {
+            LdapMsg {
+                msgid: _,
+                op: LdapOp::BindResponse(bind_resp),
+                ctrl,
+            } => {
+                op.complete();
+                Ok((bind_resp, ctrl))
             }
-            Err(_) => {
-                error!("timeout during transmit to ldap server");
-                return Err(LdapError::Transport);
+            msg => {
+                trace!(?msg);
+                Err(LdapError::InvalidProtocolState)
             }
-        };
+        }
+    }
 
-        let mut entries = Vec::new();
-        loop {
-            match timeout(LDAP_CLIENT_IO_TIMEOUT, self.r.next()).await {
-                // This terminates the iteration of entries.
-                Ok(Some(Ok(LdapMsg {
-                    msgid,
-                    op: LdapOp::SearchResultDone(search_res),
-                    ctrl,
-                }))) => {
-                    if msgid == ck_msgid {
-                        break Ok((entries, search_res, ctrl));
-                    } else {
-                        error!("invalid msgid, sequence error.");
-                        break Err(LdapError::InvalidProtocolState);
-                    }
-                }
-                Ok(Some(Ok(LdapMsg {
-                    msgid,
-                    op: LdapOp::SearchResultEntry(search_entry),
-                    ctrl,
-                }))) => {
-                    if msgid == ck_msgid {
-                        entries.push((search_entry, ctrl))
-                    } else {
-                        error!("invalid msgid, sequence error.");
-                        break Err(LdapError::InvalidProtocolState);
-                    }
-                }
-                Ok(Some(Ok(msg))) => {
-                    trace!(?msg);
-                    break Err(LdapError::InvalidProtocolState);
-                }
-                Ok(Some(Err(e))) => {
-                    error!(?e, "unable to receive from ldap server");
-                    break Err(LdapError::Transport);
-                }
-                Ok(None) => {
-                    error!("connection closed");
-                    break Err(LdapError::Transport);
-                }
-                Err(_) => {
-                    error!("connection timeout");
-                    break Err(LdapError::Transport);
-                }
+    pub async fn compare(
+        &self,
+        cr: LdapCompareRequest,
+        ctrl: Vec<LdapControl>,
+    ) -> Result<(LdapResult, Vec<LdapControl>), LdapError> {
+        let res = self.compare_inner(cr, ctrl).await;
+        if res.is_err() {
+            self.shared.fail();
+        }
+        res
+    }
+
+    async fn compare_inner(
+        &self,
+        cr: LdapCompareRequest,
+        ctrl: Vec<LdapControl>,
+    ) -> Result<(LdapResult, Vec<LdapControl>), LdapError> {
+        let mut op = self.start(LdapOp::CompareRequest(cr), ctrl)?;
+
+        match op.next().await? {
+            LdapMsg {
+                msgid: _,
+                op: LdapOp::CompareResult(compare_res),
+                ctrl,
+            } => {
+                op.complete();
+                Ok((compare_res, ctrl))
+            }
+            msg => {
+                trace!(?msg);
+                Err(LdapError::InvalidProtocolState)
+            }
+        }
+    }
+
+    /// Perform an add, modify, delete or modify dn request.
+    pub async fn write(
+        &self,
+        op: LdapOp,
+        ctrl: Vec<LdapControl>,
+    ) -> Result<(LdapResult, Vec<LdapControl>), LdapError> {
+        let res = self.write_inner(op, ctrl).await;
+        if res.is_err() {
+            self.shared.fail();
+        }
+        res
+    }
+
+    async fn write_inner(
+        &self,
+        op: LdapOp,
+        ctrl: Vec<LdapControl>,
+    ) -> Result<(LdapResult, Vec<LdapControl>), LdapError> {
+        let mut pending = self.start(op, ctrl)?;
+
+        match pending.next().await? {
+            LdapMsg {
+                msgid: _,
+                op:
+                    LdapOp::AddResponse(write_res)
+                    | LdapOp::ModifyResponse(write_res)
+                    | LdapOp::DelResponse(write_res)
+                    | LdapOp::ModifyDNResponse(write_res),
+                ctrl,
+            } => {
+                pending.complete();
+                Ok((write_res, ctrl))
+            }
+            msg => {
+                trace!(?msg);
+                Err(LdapError::InvalidProtocolState)
+            }
+        }
+    }
+
+    /// Start a search. The entries are returned by the stream as they are received.
+    pub fn search(
+        &self,
+        sr: LdapSearchRequest,
+        ctrl: Vec<LdapControl>,
+    ) -> Result<SearchStream<'_>, LdapError> {
+        match self.start(LdapOp::SearchRequest(sr), ctrl) {
+            Ok(op) => Ok(SearchStream { op }),
+            Err(err) => {
+                self.shared.fail();
+                Err(err)
+            }
+        }
+    }
+}
+
+pub enum SearchItem {
+    Entry(LdapSearchResultEntry, Vec<LdapControl>),
+    Done(LdapResult, Vec<LdapControl>),
+}
+
+/// A search in progress. If it's dropped before it is done, the search is abandoned.
+pub struct SearchStream<'a> {
+    op: PendingOp<'a>,
+}
+
+impl SearchStream<'_> {
+    /// The next entry of the search, or the result once the search is done. This must
+    /// not be called again after the result.
+    pub async fn next(&mut self) -> Result<SearchItem, LdapError> {
+        let res = self.next_inner().await;
+        if res.is_err() {
+            self.op.client.shared.fail();
+        }
+        res
+    }
+
+    async fn next_inner(&mut self) -> Result<SearchItem, LdapError> {
+        match self.op.next().await? {
+            // This terminates the iteration of entries.
+            LdapMsg {
+                msgid: _,
+                op: LdapOp::SearchResultDone(search_res),
+                ctrl,
+            } => {
+                self.op.complete();
+                Ok(SearchItem::Done(search_res, ctrl))
+            }
+            LdapMsg {
+                msgid: _,
+                op: LdapOp::SearchResultEntry(search_entry),
+                ctrl,
+            } => Ok(SearchItem::Entry(search_entry, ctrl)),
+            msg => {
+                trace!(?msg);
+                Err(LdapError::InvalidProtocolState)
             }
         }
     }
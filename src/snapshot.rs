@@ -0,0 +1,237 @@
+// The search cache can be saved to disk, and loaded when the proxy starts, so that a
+// restart doesn't send every search to the ldap server. Cached values expire at an
+// Instant, which can't outlive the process, so they are stored with the wall-clock
+// time at which they expire instead. Attribute values are stored as base64, and the
+// file may only be read by its owner, as it holds the results of searches.
+
+use crate::proxy::{CachedValue, SearchCacheKey};
+use crate::write_log::WriteLog;
+use crate::AppState;
+use concread::arcache::ARCache;
+use ldap3_proto::control::LdapControl;
+use ldap3_proto::proto::LdapPartialAttribute;
+use ldap3_proto::proto::{LdapResult, LdapSearchResultEntry};
+use serde::{Deserialize, Serialize};
+use serde_with::base64::Base64;
+use serde_with::serde_as;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::num::NonZeroUsize;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::watch;
+use tracing::{debug, error};
+
+// Snapshots of other versions are ignored.
+const SNAPSHOT_VERSION: u32 = 2;
+
+// Saves may overlap at shutdown, and must not write the same file at once.
+static SAVE_LOCK: Mutex<()> = Mutex::new(());
+
+type SearchCache = ARCache<SearchCacheKey, CachedValue>;
+
+#[serde_as]
+#[derive(Serialize, Deserialize)]
+struct SnapshotAttribute {
+    atype: String,
+    #[serde_as(as = "Vec<Base64>")]
+    vals: Vec<Vec<u8>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotEntry {
+    dn: String,
+    attributes: Vec<SnapshotAttribute>,
+    ctrl: Vec<LdapControl>,
+}
+
+impl SnapshotEntry {
+    fn new(entry: &LdapSearchResultEntry, ctrl: &[LdapControl]) -> Self {
+        SnapshotEntry {
+            dn: entry.dn.clone(),
+            attributes: entry
+                .attributes
+                .iter()
+                .map(|attr| SnapshotAttribute {
+                    atype: attr.atype.clone(),
+                    vals: attr.vals.clone(),
+                })
+                .collect(),
+            ctrl: ctrl.to_vec(),
+        }
+    }
+
+    fn into_entry(self) -> (LdapSearchResultEntry, Vec<LdapControl>) {
+        let entry = LdapSearchResultEntry {
+            dn: self.dn,
+            attributes: self
+                .attributes
+                .into_iter()
+                .map(|attr| LdapPartialAttribute {
+                    atype: attr.atype,
+                    vals: attr.vals,
+                })
+                .collect(),
+        };
+        (entry, self.ctrl)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotValue {
+    valid_until: SystemTime,
+    entries: Vec<SnapshotEntry>,
+    result: LdapResult,
+    ctrl: Vec<LdapControl>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    version: u32,
+    values: Vec<(SearchCacheKey, SnapshotValue)>,
+}
+
+/// Write the values in the cache that are still valid, and haven't been changed by a
+/// write in the log, to the path, returning how many were written. The snapshot
+/// replaces the file only once it is complete.
+pub fn save_cache(cache: &SearchCache, write_log: &WriteLog, path: &Path) -> io::Result<usize> {
+    let now = Instant::now();
+    let wall_now = SystemTime::now();
+
+    let values: Vec<_> = {
+        let cache_write_txn = cache.write();
+        cache_write_txn
+            .iter()
+            .filter(|(cache_key, cache_value)| {
+                cache_value.valid_until > now
+                    && !write_log.invalidates(cache_value.generation, cache_key)
+            })
+            .map(|(cache_key, cache_value)| {
+                (
+                    cache_key.clone(),
+                    SnapshotValue {
+                        valid_until: wall_now + (cache_value.valid_until - now),
+                        entries: cache_value
+                            .entries
+                            .iter()
+                            .map(|(entry, ctrl)| SnapshotEntry::new(entry, ctrl))
+                            .collect(),
+                        result: cache_value.result.clone(),
+                        ctrl: cache_value.ctrl.clone(),
+                    },
+                )
+            })
+            .collect()
+        // The transaction isn't committed, as nothing was changed.
+    };
+
+    let count = values.len();
+    let snapshot = Snapshot {
+        version: SNAPSHOT_VERSION,
+        values,
+    };
+
+    let _guard = SAVE_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    // A file left by a failed save may have been created with other permissions.
+    match std::fs::remove_file(&tmp_path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp_path)?;
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer(&mut writer, &snapshot)?;
+    writer.flush()?;
+    drop(writer);
+
+    std::fs::rename(&tmp_path, path)?;
+    Ok(count)
+}
+
+/// Add the values in the snapshot at the path that are still valid to the cache,
+/// returning how many were added.
+pub fn load_cache(cache: &SearchCache, path: &Path) -> io::Result<usize> {
+    let reader = BufReader::new(File::open(path)?);
+    let snapshot: Snapshot = serde_json::from_reader(reader)?;
+
+    if snapshot.version != SNAPSHOT_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported snapshot version {}", snapshot.version),
+        ));
+    }
+
+    let now = Instant::now();
+    let wall_now = SystemTime::now();
+
+    let mut count = 0;
+    let mut cache_write_txn = cache.write();
+    for (cache_key, snapshot_value) in snapshot.values {
+        // Values that have expired, including while the proxy was stopped, are dropped.
+        let Ok(remaining) = snapshot_value.valid_until.duration_since(wall_now) else {
+            continue;
+        };
+
+        let cache_value = CachedValue {
+            valid_until: now + remaining,
+            // Any writes that are in the log are checked against the value.
+            generation: 0,
+            entries: snapshot_value
+                .entries
+                .into_iter()
+                .map(SnapshotEntry::into_entry)
+                .collect(),
+            result: snapshot_value.result,
+            ctrl: snapshot_value.ctrl,
+        };
+        if let Some(cache_value_size) = NonZeroUsize::new(cache_value.size()) {
+            cache_write_txn.insert_sized(cache_key, cache_value, cache_value_size);
+            count += 1;
+        }
+    }
+    cache_write_txn.commit();
+
+    Ok(count)
+}
+
+/// Save the cache of the current app state periodically, until the app state can no
+/// longer change.
+pub async fn cache_snapshot_task(
+    mut app_state_rx: watch::Receiver<Arc<AppState>>,
+    path: PathBuf,
+    interval: Duration,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    // The first tick completes immediately, and there is nothing to save yet.
+    ticker.tick().await;
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            Err(_) = app_state_rx.changed() => break,
+        }
+
+        // A reload may replace the cache, so the current one is always saved.
+        let app_state = app_state_rx.borrow_and_update().clone();
+        let path = path.clone();
+        match tokio::task::spawn_blocking(move || {
+            save_cache(&app_state.cache, &app_state.write_log, &path)
+        })
+        .await
+        {
+            Ok(Ok(count)) => debug!("Saved {} cached results", count),
+            Ok(Err(err)) => error!(?err, "Unable to save the cache"),
+            Err(err) => error!(?err, "Unable to save the cache"),
+        }
+    }
+    debug!("Stopped saving the cache");
+}
@@ -0,0 +1,86 @@
+// Counts of how searches were answered, so that it can be seen how well the cache
+// works, and how often results that had expired were sent to clients.
+
+use crate::AppState;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{debug, info};
+
+#[derive(Debug, Default)]
+pub struct CacheStats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    stale_while_revalidate: AtomicU64,
+    stale_if_error: AtomicU64,
+}
+
+/// The counts of a `CacheStats` at one time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheCounts {
+    /// Searches answered from the cache.
+    pub hits: u64,
+    /// Searches that were sent to the ldap server.
+    pub misses: u64,
+    /// Searches answered with an expired result while it was searched for again.
+    pub stale_while_revalidate: u64,
+    /// Searches answered with an expired result as the ldap server couldn't be searched.
+    pub stale_if_error: u64,
+}
+
+impl CacheStats {
+    pub fn record_hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_stale_while_revalidate(&self) {
+        self.stale_while_revalidate.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_stale_if_error(&self) {
+        self.stale_if_error.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn counts(&self) -> CacheCounts {
+        CacheCounts {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            stale_while_revalidate: self.stale_while_revalidate.load(Ordering::Relaxed),
+            stale_if_error: self.stale_if_error.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Log the counts of the current app state periodically, until the app state can no
+/// longer change.
+pub async fn cache_stats_task(
+    mut app_state_rx: watch::Receiver<Arc<AppState>>,
+    interval: Duration,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    // The first tick completes immediately, and there is nothing to count yet.
+    ticker.tick().await;
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            Err(_) = app_state_rx.changed() => break,
+        }
+
+        // The counts are kept over a reload, but the app state that holds them is
+        // replaced.
+        let counts = app_state_rx.borrow_and_update().cache_stats.counts();
+        info!(
+            hits = counts.hits,
+            misses = counts.misses,
+            stale_while_revalidate = counts.stale_while_revalidate,
+            stale_if_error = counts.stale_if_error,
+            "Search cache statistics"
+        );
+    }
+    debug!("Stopped logging cache statistics");
+}
@@ -0,0 +1,153 @@
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+use tracing::{debug, error, info};
+
+#[derive(Debug)]
+pub enum TlsError {
+    ChainAccess,
+    ChainParse,
+    ChainEmpty,
+    KeyParse,
+    KeyPair,
+    LockPoisoned,
+}
+
+#[derive(Debug)]
+struct LoadedCert {
+    tls_chain: PathBuf,
+    tls_key: PathBuf,
+    modified: (Option<SystemTime>, Option<SystemTime>),
+    certified_key: Arc<CertifiedKey>,
+}
+
+/// A server certificate resolver that allows the certificate chain and key to be
+/// replaced while the listener is running. New handshakes use the most recently
+/// loaded certificate, and a failed reload leaves the current certificate in place.
+#[derive(Debug)]
+pub struct ReloadableCertResolver {
+    provider: Arc<CryptoProvider>,
+    current: RwLock<LoadedCert>,
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn load_cert(
+    tls_chain: &Path,
+    tls_key: &Path,
+    provider: &CryptoProvider,
+) -> Result<LoadedCert, TlsError> {
+    // Take the modification times first, so that if the files change while we
+    // are loading them, we will notice and load them again.
+    let modified = (modified_time(tls_chain), modified_time(tls_key));
+
+    let tls_chain_iter = CertificateDer::pem_file_iter(tls_chain).map_err(|err| {
+        error!(?err, "Failed to access tls certificate chain");
+        TlsError::ChainAccess
+    })?;
+
+    let chain = tls_chain_iter
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| {
+            error!(?err, "Failed to parse tls certificate chain");
+            TlsError::ChainParse
+        })?;
+
+    if chain.is_empty() {
+        error!("tls certificate chain contains no certificates");
+        return Err(TlsError::ChainEmpty);
+    }
+
+    let key = PrivateKeyDer::from_pem_file(tls_key).map_err(|err| {
+        error!(?err, "Error reading server private key");
+        TlsError::KeyParse
+    })?;
+
+    let certified_key = CertifiedKey::from_der(chain, key, provider).map_err(|err| {
+        error!(
+            ?err,
+            "tls certificate chain and private key are not a valid pair"
+        );
+        TlsError::KeyPair
+    })?;
+
+    Ok(LoadedCert {
+        tls_chain: tls_chain.to_path_buf(),
+        tls_key: tls_key.to_path_buf(),
+        modified,
+        certified_key: Arc::new(certified_key),
+    })
+}
+
+impl ReloadableCertResolver {
+    pub fn new(
+        tls_chain: &Path,
+        tls_key: &Path,
+        provider: Arc<CryptoProvider>,
+    ) -> Result<Self, TlsError> {
+        let current = load_cert(tls_chain, tls_key, &provider)?;
+        Ok(ReloadableCertResolver {
+            provider,
+            current: RwLock::new(current),
+        })
+    }
+
+    /// Load the certificate chain and key from these paths, replacing the current
+    /// certificate only if they are valid.
+    pub fn reload(&self, tls_chain: &Path, tls_key: &Path) -> Result<(), TlsError> {
+        let loaded = load_cert(tls_chain, tls_key, &self.provider)?;
+
+        let mut current = self.current.write().map_err(|_| {
+            error!("tls certificate lock poisoned");
+            TlsError::LockPoisoned
+        })?;
+        *current = loaded;
+
+        info!(?tls_chain, ?tls_key, "Reloaded tls certificate");
+        Ok(())
+    }
+
+    /// Reload the current certificate chain and key if either file has been modified
+    /// since they were last loaded. Returns true if a reload occurred.
+    pub fn reload_if_modified(&self) -> Result<bool, TlsError> {
+        let (tls_chain, tls_key, modified) = {
+            let current = self.current.read().map_err(|_| {
+                error!("tls certificate lock poisoned");
+                TlsError::LockPoisoned
+            })?;
+            (
+                current.tls_chain.clone(),
+                current.tls_key.clone(),
+                current.modified,
+            )
+        };
+
+        if (modified_time(&tls_chain), modified_time(&tls_key)) == modified {
+            debug!("tls certificate unchanged");
+            return Ok(false);
+        }
+
+        self.reload(&tls_chain, &tls_key).map(|()| true)
+    }
+
+    /// The certificate chain and key that new handshakes use.
+    pub fn certified_key(&self) -> Option<Arc<CertifiedKey>> {
+        self.current
+            .read()
+            .ok()
+            .map(|current| current.certified_key.clone())
+    }
+}
+
+impl ResolvesServerCert for ReloadableCertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.certified_key()
+    }
+}
@@ -0,0 +1,337 @@
+use crate::proxy::{BasicLdapClient, LdapError, LdapUpstream, SearchItem};
+use crate::LDAP_CLIENT_CONN_TIMEOUT;
+use futures_util::future::join_all;
+use ldap3_proto::proto::{LdapDerefAliases, LdapFilter, LdapSearchRequest, LdapSearchScope};
+use percent_encoding::percent_decode_str;
+use rustls::pki_types::ServerName;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::time::timeout;
+use tokio_rustls::TlsConnector;
+use tracing::{debug, error, info, trace, warn};
+use url::Url;
+
+#[derive(Debug, Default)]
+struct Health {
+    consecutive_failures: u32,
+    // While set and in the future, the circuit is open and the server is skipped.
+    down_until: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub struct UpstreamServer {
+    pub name: String,
+    pub upstream: LdapUpstream,
+    pub priority: u32,
+    pub weight: u32,
+    health: Mutex<Health>,
+}
+
+impl UpstreamServer {
+    pub fn new(name: String, upstream: LdapUpstream, priority: u32, weight: u32) -> Self {
+        UpstreamServer {
+            name,
+            upstream,
+            priority,
+            weight,
+            health: Mutex::new(Health::default()),
+        }
+    }
+
+    fn is_available(&self, now: Instant) -> bool {
+        self.health
+            .lock()
+            .map(|health| health.down_until.is_none_or(|down_until| now >= down_until))
+            .unwrap_or(true)
+    }
+}
+
+/// The set of upstream ldap servers that client connections are made to. Servers are
+/// tried in priority order (lowest first), and servers of the same priority share new
+/// connections according to their weight. A server that fails repeatedly is skipped
+/// until it recovers, or until it is due to be retried.
+pub struct UpstreamSet {
+    servers: Vec<UpstreamServer>,
+    tls_connector: TlsConnector,
+    max_ber_size: Option<usize>,
+    failure_threshold: u32,
+    retry_after: Duration,
+    connect_timeout: Duration,
+    rotation: AtomicUsize,
+}
+
+impl UpstreamSet {
+    pub fn new(
+        servers: Vec<UpstreamServer>,
+        tls_connector: TlsConnector,
+        max_ber_size: Option<usize>,
+        failure_threshold: u32,
+        retry_after: Duration,
+        connect_timeout: Duration,
+    ) -> Self {
+        UpstreamSet {
+            servers,
+            tls_connector,
+            max_ber_size,
+            failure_threshold: failure_threshold.max(1),
+            retry_after,
+            connect_timeout,
+            rotation: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn record_success(&self, server: &UpstreamServer) {
+        if let Ok(mut health) = server.health.lock() {
+            if health.down_until.is_some() {
+                info!(upstream = %server.name, "ldap server has recovered");
+            }
+            *health = Health::default();
+        }
+    }
+
+    pub fn record_failure(&self, server: &UpstreamServer) {
+        if let Ok(mut health) = server.health.lock() {
+            health.consecutive_failures = health.consecutive_failures.saturating_add(1);
+            if health.consecutive_failures >= self.failure_threshold {
+                if health.down_until.is_none() {
+                    warn!(upstream = %server.name, failures = %health.consecutive_failures, "ldap server marked as down");
+                }
+                health.down_until = Some(Instant::now() + self.retry_after);
+            }
+        }
+    }
+
+    /// The servers to attempt for a new connection, in the order they should be tried.
+    pub fn candidates(&self) -> Vec<&UpstreamServer> {
+        let now = Instant::now();
+        let rotation = self.rotation.fetch_add(1, Ordering::Relaxed);
+
+        let mut candidates: Vec<_> = self
+            .servers
+            .iter()
+            .filter(|server| server.is_available(now))
+            .collect();
+
+        if candidates.is_empty() {
+            // Rather than refusing every connection until a server is due to be retried,
+            // the servers that are down are tried, in case one has recovered.
+            debug!("No ldap servers are available, trying the servers that are down");
+            candidates = self.servers.iter().collect();
+        }
+
+        candidates.sort_by_key(|server| server.priority);
+
+        // Within a priority, start from a server chosen in proportion to its weight.
+        for group in candidates.chunk_by_mut(|a, b| a.priority == b.priority) {
+            let total_weight: usize = group.iter().map(|server| server.weight as usize).sum();
+            if total_weight == 0 {
+                continue;
+            }
+            let mut point = rotation % total_weight;
+            let start = group
+                .iter()
+                .position(|server| {
+                    let weight = server.weight as usize;
+                    if point < weight {
+                        true
+                    } else {
+                        point -= weight;
+                        false
+                    }
+                })
+                .unwrap_or_default();
+            group.rotate_left(start);
+        }
+
+        candidates
+    }
+
+    /// Connect to the first available upstream server.
+    pub async fn connect(&self) -> Result<BasicLdapClient, LdapError> {
+        let candidates = self.candidates();
+
+        if candidates.is_empty() {
+            error!("No ldap servers are available");
+            return Err(LdapError::ConnectError);
+        }
+
+        for server in candidates {
+            match BasicLdapClient::build(
+                &server.upstream,
+                &self.tls_connector,
+                self.max_ber_size,
+                self.connect_timeout,
+            )
+            .await
+            {
+                Ok(client) => {
+                    debug!(upstream = %server.name, "connected to ldap server");
+                    self.record_success(server);
+                    return Ok(client);
+                }
+                Err(err) => {
+                    warn!(upstream = %server.name, ?err, "unable to connect to ldap server, trying next server");
+                    self.record_failure(server);
+                }
+            }
+        }
+
+        Err(LdapError::ConnectError)
+    }
+
+    async fn probe(&self, server: &UpstreamServer) -> Result<(), LdapError> {
+        let client = BasicLdapClient::build(
+            &server.upstream,
+            &self.tls_connector,
+            self.max_ber_size,
+            self.connect_timeout,
+        )
+        .await?;
+
+        // Read the root dse. Any ldap response, even a refusal, shows the server is
+        // able to process requests.
+        let sr = LdapSearchRequest {
+            base: "".to_string(),
+            scope: LdapSearchScope::Base,
+            aliases: LdapDerefAliases::Never,
+            sizelimit: 1,
+            timelimit: 0,
+            typesonly: false,
+            filter: LdapFilter::Present("objectClass".to_string()),
+            attrs: vec!["1.1".to_string()],
+        };
+
+        let mut search_stream = client.search(sr, Vec::with_capacity(0))?;
+        while let SearchItem::Entry(..) = search_stream.next().await? {}
+        Ok(())
+    }
+
+    /// Probe every server, updating their health.
+    pub async fn health_check(&self) {
+        let probes = self.servers.iter().map(|server| async move {
+            let result = timeout(LDAP_CLIENT_CONN_TIMEOUT, self.probe(server))
+                .await
+                .unwrap_or(Err(LdapError::ConnectError));
+
+            match result {
+                Ok(()) => {
+                    trace!(upstream = %server.name, "health check passed");
+                    self.record_success(server);
+                }
+                Err(err) => {
+                    warn!(upstream = %server.name, ?err, "health check failed");
+                    self.record_failure(server);
+                }
+            }
+        });
+
+        join_all(probes).await;
+    }
+}
+
+/// Periodically probe the health of the upstream servers. This stops once the set of
+/// servers is no longer in use, such as after a configuration reload.
+pub async fn health_check_task(upstreams: Weak<UpstreamSet>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+
+        let Some(upstreams) = upstreams.upgrade() else {
+            break;
+        };
+
+        upstreams.health_check().await;
+    }
+    debug!("Stopped ldap server health checks");
+}
+
+/// Why the url of an ldap server is invalid.
+#[derive(Debug)]
+pub enum UpstreamUrlError {
+    Scheme,
+    SocketPath,
+    Hostname,
+    Resolve,
+}
+
+/// The ldap server of a url, which is one of `ldaps://`, `ldap://`, which uses StartTLS
+/// unless `insecure_plaintext` is set, or `ldapi://` with the percent encoded path of a
+/// unix socket as its host.
+pub fn build_upstream(
+    url: &Url,
+    insecure_plaintext: bool,
+) -> Result<LdapUpstream, UpstreamUrlError> {
+    let default_port = match url.scheme() {
+        "ldaps" => 636,
+        "ldap" => 389,
+        "ldapi" => {
+            // The socket path is the percent encoded host of the url.
+            let Some(host) = url.host_str() else {
+                error!("Unable to determine socket path from ldapi url");
+                return Err(UpstreamUrlError::SocketPath);
+            };
+
+            let path = match percent_decode_str(host).decode_utf8() {
+                Ok(p) => PathBuf::from(p.as_ref()),
+                Err(err) => {
+                    error!(?err, "Invalid socket path in ldapi url");
+                    return Err(UpstreamUrlError::SocketPath);
+                }
+            };
+
+            return Ok(LdapUpstream::Ldapi { path });
+        }
+        _ => {
+            error!("Unable to proceed. ldap_url must be one of ldaps://, ldap:// or ldapi://");
+            return Err(UpstreamUrlError::Scheme);
+        }
+    };
+
+    let hostname = match url.host_str() {
+        Some(s) => s,
+        None => {
+            error!("Unable to determine hostname from url");
+            return Err(UpstreamUrlError::Hostname);
+        }
+    };
+
+    let addrs = match url.socket_addrs(|| Some(default_port)) {
+        Ok(a) => a,
+        Err(e) => {
+            error!(?e, "url address resolver error");
+            return Err(UpstreamUrlError::Resolve);
+        }
+    };
+
+    if addrs.is_empty() {
+        error!("url address resolved to no addresses");
+        return Err(UpstreamUrlError::Resolve);
+    }
+
+    if url.scheme() == "ldap" && insecure_plaintext {
+        warn!("ldap_insecure_plaintext is set, connections to the remote ldap server will NOT be encrypted");
+        return Ok(LdapUpstream::Plaintext { addrs });
+    }
+
+    let tls_hostname = match ServerName::try_from(hostname.to_string()) {
+        Ok(h) => h,
+        Err(err) => {
+            error!(?err, "Invalid LDAP Server Hostname");
+            return Err(UpstreamUrlError::Hostname);
+        }
+    };
+
+    if url.scheme() == "ldap" {
+        Ok(LdapUpstream::StartTls {
+            addrs,
+            tls_hostname,
+        })
+    } else {
+        Ok(LdapUpstream::Ldaps {
+            addrs,
+            tls_hostname,
+        })
+    }
+}
@@ -0,0 +1,98 @@
+// Writes made through the proxy stop the cached results that they could have changed
+// from being used. Rather than search the whole cache on every write, each cached
+// result holds the generation of the log from before it was searched for, and is
+// checked against the writes made since when it is found in the cache.
+
+use crate::proxy::SearchCacheKey;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+#[derive(Debug)]
+struct Write {
+    generation: u64,
+    written_at: Instant,
+    dns: Vec<String>,
+}
+
+#[derive(Debug)]
+struct Writes {
+    generation: u64,
+    writes: VecDeque<Write>,
+    /// How long a write is kept, which must be as long as a result searched for before
+    /// it may be used.
+    retention: Duration,
+}
+
+#[derive(Debug)]
+pub struct WriteLog {
+    writes: Mutex<Writes>,
+}
+
+impl WriteLog {
+    pub fn new(retention: Duration) -> Self {
+        WriteLog {
+            writes: Mutex::new(Writes {
+                generation: 0,
+                writes: VecDeque::new(),
+                retention,
+            }),
+        }
+    }
+
+    /// Keep writes for at least this long. The log is kept over a reload, and the
+    /// results cached before it may still be used for as long as they were cached for,
+    /// so the retention is never shortened.
+    pub fn extend_retention(&self, retention: Duration) {
+        let mut writes = self.writes.lock().unwrap_or_else(|err| err.into_inner());
+        writes.retention = writes.retention.max(retention);
+    }
+
+    /// The generation of the log, which a result searched for now is cached with.
+    pub fn generation(&self) -> u64 {
+        self.writes
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .generation
+    }
+
+    /// Record a successful write to these dns.
+    pub fn record(&self, dns: Vec<String>) {
+        let now = Instant::now();
+        let mut writes = self.writes.lock().unwrap_or_else(|err| err.into_inner());
+
+        let retention = writes.retention;
+        while writes
+            .writes
+            .front()
+            .is_some_and(|write| now.duration_since(write.written_at) > retention)
+        {
+            writes.writes.pop_front();
+        }
+
+        writes.generation += 1;
+        let generation = writes.generation;
+        writes.writes.push_back(Write {
+            generation,
+            written_at: now,
+            dns,
+        });
+        debug!("{} writes are kept", writes.writes.len());
+    }
+
+    /// Check if a write since the generation could have changed the cached result.
+    pub fn invalidates(&self, generation: u64, cache_key: &SearchCacheKey) -> bool {
+        let writes = self.writes.lock().unwrap_or_else(|err| err.into_inner());
+        if writes.generation == generation {
+            return false;
+        }
+
+        writes
+            .writes
+            .iter()
+            .rev()
+            .take_while(|write| write.generation > generation)
+            .any(|write| write.dns.iter().any(|dn| cache_key.affected_by(dn)))
+    }
+}
@@ -1,9 +1,63 @@
 // use ldap_proxy::proxy::BasicLdapClient;
 
-use ldap3_proto::proto::LdapResult;
-use ldap_proxy::proxy::CachedValue;
-use ldap_proxy::Config;
+use concread::arcache::{ARCache, ARCacheBuilder};
+use futures_util::sink::SinkExt;
+use futures_util::stream::StreamExt;
+use ldap3_proto::proto::{
+    LdapBindCred, LdapBindRequest, LdapBindResponse, LdapDerefAliases, LdapExtendedRequest,
+    LdapExtendedResponse, LdapModify, LdapModifyRequest, LdapModifyType, LdapMsg, LdapOp,
+    LdapPartialAttribute, LdapResult, LdapResultCode, LdapSearchRequest, LdapSearchResultEntry,
+};
+use ldap3_proto::LdapCodec;
+use ldap3_proto::{LdapFilter, LdapSearchScope};
+use ldap_proxy::canonical::{canonical_attrs, canonical_filter, canonical_search};
+use ldap_proxy::dn::{canonical_dn, dn_depth_below, dn_eq, dn_parent};
+use ldap_proxy::net::IpNetwork;
+use ldap_proxy::pool::BindPool;
+use ldap_proxy::prewarm::prewarm_task;
+use ldap_proxy::proxy::{
+    refresh_search, starttls_client_process, BasicLdapClient, CachedValue, ClientSession,
+    LdapError, LdapUpstream, SearchCacheKey,
+};
+use ldap_proxy::snapshot::{load_cache, save_cache};
+use ldap_proxy::tls::{ReloadableCertResolver, TlsError};
+use ldap_proxy::upstream::{build_upstream, UpstreamServer, UpstreamSet};
+use ldap_proxy::write_log::WriteLog;
+use ldap_proxy::{
+    AppState, BindDenialResponse, BindMap, Config, DenialResponse, DnConfig, GroupPolicy,
+};
+use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tokio_util::codec::{Framed, FramedRead, FramedWrite};
+use url::Url;
+
+const OID_STARTTLS: &str = "1.3.6.1.4.1.1466.20037";
+
+/// The settings that every config needs.
+const CONFIG_HEADER: &str = r#"
+bind = "127.0.0.1:3636"
+tls_chain = "/etc/ldap-proxy/chain.pem"
+tls_key = "/etc/ldap-proxy/key.pem"
+ldap_ca = "/etc/ldap-proxy/ldap-ca.pem"
+"#;
+
+/// Parse a config of these settings, and the settings that every config needs.
+fn parse_config(settings: &str) -> Config {
+    toml::from_str::<Config>(&format!("{}{}", CONFIG_HEADER, settings))
+        .expect("Failed to load config")
+}
 
 #[test]
 fn test_config_load() {
@@ -18,10 +72,829 @@ fn test_config_load() {
     );
 }
 
+#[test]
+fn test_config_ldap_servers() {
+    let config = parse_config(
+        r#"
+ldap_servers = [
+    { url = "ldaps://ldap1.example.com" },
+    { url = "ldaps://ldap2.example.com", priority = 1, weight = 3 },
+]
+
+["cn=Administrator"]
+"#,
+    );
+
+    assert!(config.ldap_url.is_none());
+    assert_eq!(config.ldap_servers.len(), 2);
+    assert_eq!(config.ldap_servers[0].priority, 0);
+    assert_eq!(config.ldap_servers[0].weight, 1);
+    assert_eq!(config.ldap_servers[1].priority, 1);
+    assert_eq!(config.ldap_servers[1].weight, 3);
+    assert!(config.binddn_map.contains_key("cn=Administrator"));
+}
+
+#[test]
+fn test_config_denial_responses() {
+    let config =
+        toml::from_str::<Config>(include_str!("test_config.toml")).expect("Failed to load config");
+    assert_eq!(config.denial_response, DenialResponse::EmptySuccess);
+    assert_eq!(
+        config.bind_denial_response,
+        BindDenialResponse::OperationsError
+    );
+
+    let config = parse_config(
+        r#"
+denial_response = "insufficient_access_rights"
+bind_denial_response = "invalid_credentials"
+
+["cn=user"]
+denial_response = "unwilling_to_perform"
+denial_message = "not allowed"
+"#,
+    );
+
+    assert_eq!(
+        config.denial_response,
+        DenialResponse::InsufficientAccessRights
+    );
+    assert_eq!(
+        config.bind_denial_response,
+        BindDenialResponse::InvalidCredentials
+    );
+    let dnconfig = config.binddn_map.get("cn=user").expect("Missing bind map");
+    assert_eq!(
+        dnconfig.denial_response,
+        Some(DenialResponse::UnwillingToPerform)
+    );
+    assert_eq!(dnconfig.denial_message.as_deref(), Some("not allowed"));
+}
+
+#[test]
+fn test_config_cache_result_codes() {
+    let config =
+        toml::from_str::<Config>(include_str!("test_config.toml")).expect("Failed to load config");
+    assert_eq!(config.cache_negative_timeout, 60);
+    assert!(config.cache_result_codes.contains(&LdapResultCode::Success));
+    assert!(config
+        .cache_result_codes
+        .contains(&LdapResultCode::NoSuchObject));
+    assert!(!config.cache_result_codes.contains(&LdapResultCode::Busy));
+    assert!(!config
+        .cache_result_codes
+        .contains(&LdapResultCode::TimeLimitExceeded));
+
+    let config = parse_config(
+        r#"
+cache_negative_timeout = 5
+cache_result_codes = ["success", "compare_true", "compare_false"]
+"#,
+    );
+
+    assert_eq!(config.cache_negative_timeout, 5);
+    assert_eq!(
+        config.cache_result_codes,
+        vec![
+            LdapResultCode::Success,
+            LdapResultCode::CompareTrue,
+            LdapResultCode::CompareFalse
+        ]
+    );
+}
+
+#[test]
+fn test_config_cache_prewarm() {
+    let config =
+        toml::from_str::<Config>(include_str!("test_config.toml")).expect("Failed to load config");
+    assert!(config.cache_prewarm.is_empty());
+    assert_eq!(config.cache_refresh_ahead, 60);
+
+    let config = parse_config(
+        r#"
+cache_refresh_ahead = 120
+
+[[cache_prewarm]]
+base = "ou=groups,o=example"
+scope = "one_level"
+filter = "(objectClass=posixGroup)"
+attrs = ["cn", "memberUid"]
+
+[[cache_prewarm]]
+bind_dn = "cn=app"
+bind_secret = "12345"
+base = "ou=people,o=example"
+scope = "subtree"
+filter = "(uid=*)"
+
+["cn=app"]
+"#,
+    );
+
+    assert_eq!(config.cache_refresh_ahead, 120);
+    assert_eq!(config.cache_prewarm.len(), 2);
+    assert!(config.binddn_map.contains_key("cn=app"));
+
+    let groups = &config.cache_prewarm[0];
+    assert_eq!(groups.bind_dn, "");
+    assert_eq!(groups.bind_secret, None);
+    assert_eq!(
+        groups.search_request(),
+        LdapSearchRequest {
+            base: "ou=groups,o=example".to_string(),
+            scope: LdapSearchScope::OneLevel,
+            aliases: LdapDerefAliases::Never,
+            sizelimit: 0,
+            timelimit: 0,
+            typesonly: false,
+            filter: LdapFilter::Equality("objectclass".to_string(), "posixGroup".to_string()),
+            attrs: vec!["cn".to_string(), "memberUid".to_string()],
+        }
+    );
+
+    let people = &config.cache_prewarm[1];
+    assert_eq!(people.bind_dn, "cn=app");
+    assert_eq!(people.bind_secret.as_deref(), Some("12345"));
+    assert!(people.search_request().attrs.is_empty());
+}
+
+#[test]
+fn test_config_cache_stale() {
+    let config =
+        toml::from_str::<Config>(include_str!("test_config.toml")).expect("Failed to load config");
+    assert_eq!(config.cache_stale_while_revalidate, 0);
+    assert_eq!(config.cache_stale_if_error, 0);
+    assert_eq!(config.cache_stats_interval, 0);
+
+    let config = parse_config(
+        r#"
+cache_stale_while_revalidate = 30
+cache_stale_if_error = 3600
+cache_stats_interval = 60
+"#,
+    );
+
+    assert_eq!(config.cache_stale_while_revalidate, 30);
+    assert_eq!(config.cache_stale_if_error, 3600);
+    assert_eq!(config.cache_stats_interval, 60);
+}
+
+#[test]
+fn test_dnconfig_compare_allowed() {
+    let dnconfig = DnConfig::default();
+    assert!(dnconfig.compare_allowed("cn=group,ou=groups,o=example", "member"));
+
+    let dnconfig = toml::from_str::<DnConfig>(
+        r#"
+allowed_compares = [
+    ["*,ou=groups,o=example", "member"],
+    ["cn=admins,o=example", "uniqueMember"],
+]
+"#,
+    )
+    .expect("Failed to load config");
+
+    assert!(dnconfig.compare_allowed("cn=group,ou=groups,o=example", "member"));
+    assert!(dnconfig.compare_allowed("CN=group,OU=groups,O=example", "MEMBER"));
+    assert!(!dnconfig.compare_allowed("cn=group,ou=Groups,o=example", "member"));
+    assert!(!dnconfig.compare_allowed("cn=group,ou=groups,o=example", "userPassword"));
+    assert!(!dnconfig.compare_allowed("ou=groups,o=example", "member"));
+    assert!(!dnconfig.compare_allowed("cn=group,ou=othergroups,o=example", "member"));
+    assert!(dnconfig.compare_allowed("cn=admins,o=example", "uniquemember"));
+    assert!(!dnconfig.compare_allowed("cn=x,cn=admins,o=example", "uniquemember"));
+    assert!(!dnconfig.compare_allowed("cn=x,cn=group,ou=groups,o=example", "member"));
+
+    // Allowed compares don't restrict searches.
+    assert!(dnconfig.query_allowed(
+        "o=example",
+        &LdapSearchScope::Subtree,
+        &LdapFilter::Present("objectclass".to_string())
+    ));
+
+    let dnconfig = toml::from_str::<DnConfig>(
+        r#"
+allowed_queries = [
+    ["o=example", "subtree", "(objectclass=*)"],
+]
+"#,
+    )
+    .expect("Failed to load config");
+
+    // Compares could reveal entries that the allowed queries hide, so a dn with allowed
+    // queries may only make allowed compares.
+    assert!(!dnconfig.compare_allowed("cn=group,ou=groups,o=example", "member"));
+    assert!(!dnconfig.query_allowed(
+        "ou=groups,o=example",
+        &LdapSearchScope::Subtree,
+        &LdapFilter::Present("objectclass".to_string())
+    ));
+
+    let dnconfig = toml::from_str::<DnConfig>(
+        r#"
+allowed_queries = [
+    ["o=example", "subtree", "(objectclass=*)"],
+]
+allowed_compares = [
+    ["*,ou=groups,o=example", "member"],
+]
+"#,
+    )
+    .expect("Failed to load config");
+
+    assert!(dnconfig.compare_allowed("cn=group,ou=groups,o=example", "member"));
+    assert!(!dnconfig.compare_allowed("cn=admins,o=example", "member"));
+}
+
+#[test]
+fn test_bindmap_patterns() {
+    let binddn_map = toml::from_str::<BTreeMap<String, DnConfig>>(
+        r#"
+["uid=admin,ou=people,o=example"]
+denial_message = "exact"
+["*,ou=people,o=example"]
+denial_message = "child"
+["**,o=example"]
+denial_message = "descendant"
+["regex:uid=svc-[a-z]+,ou=services,o=other"]
+denial_message = "regex"
+"#,
+    )
+    .expect("Failed to load config");
+    let bindmap = BindMap::new(&binddn_map).expect("Invalid bind map");
+
+    let resolve = |dn: &str| {
+        bindmap
+            .get(dn)
+            .and_then(|dnconfig| dnconfig.denial_message.as_deref())
+    };
+
+    assert_eq!(resolve("UID=admin, OU=people,o=example"), Some("exact"));
+    assert_eq!(resolve("uid=user,ou=people,o=example"), Some("child"));
+    assert_eq!(resolve("uid=svc-a,ou=people,o=example"), Some("child"));
+    assert_eq!(
+        resolve("uid=user,ou=staff,ou=people,o=example"),
+        Some("descendant")
+    );
+    assert_eq!(resolve("uid=svc-a,ou=services,o=other"), Some("regex"));
+    // The regex must match the whole dn, not only a prefix of it.
+    assert_eq!(resolve("uid=svc-a,ou=services,o=other,o=admins"), None);
+    assert_eq!(resolve("uid=svc-a,ou=servicesx,o=other"), None);
+    assert_eq!(resolve("xuid=svc-a,ou=services,o=other"), None);
+    assert_eq!(resolve("o=example"), None);
+    assert_eq!(resolve("uid=user,o=other"), None);
+
+    let binddn_map = toml::from_str::<BTreeMap<String, DnConfig>>(
+        r#"
+["regex:("]
+"#,
+    )
+    .expect("Failed to load config");
+    assert!(BindMap::new(&binddn_map).is_err());
+}
+
+#[test]
+fn test_dnconfig_limits() {
+    let dnconfig = DnConfig::default();
+    assert_eq!(dnconfig.size_limit(0), 0);
+    assert_eq!(dnconfig.size_limit(1000), 1000);
+    assert_eq!(dnconfig.time_limit(0), 0);
+
+    let dnconfig = toml::from_str::<DnConfig>(
+        r#"
+max_size_limit = 500
+max_time_limit = 30
+"#,
+    )
+    .expect("Failed to load config");
+
+    assert_eq!(dnconfig.size_limit(0), 500);
+    assert_eq!(dnconfig.size_limit(-1), 500);
+    assert_eq!(dnconfig.size_limit(10), 10);
+    assert_eq!(dnconfig.size_limit(1000), 500);
+    assert_eq!(dnconfig.time_limit(0), 30);
+    assert_eq!(dnconfig.time_limit(5), 5);
+    assert_eq!(dnconfig.time_limit(60), 30);
+}
+
+#[test]
+fn test_dnconfig_address_allowed() {
+    let addr = |addr: &str| addr.parse::<IpAddr>().expect("Invalid address");
+
+    let dnconfig = DnConfig::default();
+    assert!(dnconfig.address_allowed(addr("192.0.2.1")));
+
+    let dnconfig = toml::from_str::<DnConfig>(
+        r#"
+allowed_networks = ["10.20.0.0/24", "192.0.2.7", "2001:db8:20::/48"]
+"#,
+    )
+    .expect("Failed to load config");
+
+    assert!(dnconfig.address_allowed(addr("10.20.0.1")));
+    assert!(dnconfig.address_allowed(addr("10.20.0.255")));
+    assert!(!dnconfig.address_allowed(addr("10.20.1.1")));
+    assert!(dnconfig.address_allowed(addr("192.0.2.7")));
+    assert!(!dnconfig.address_allowed(addr("192.0.2.8")));
+    assert!(dnconfig.address_allowed(addr("::ffff:10.20.0.9")));
+    assert!(dnconfig.address_allowed(addr("2001:db8:20:1::1")));
+    assert!(!dnconfig.address_allowed(addr("2001:db8:21::1")));
+
+    let network = "0.0.0.0/0".parse::<IpNetwork>().expect("Invalid network");
+    assert!(network.contains(addr("203.0.113.1")));
+    assert!(!network.contains(addr("2001:db8::1")));
+
+    assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+    assert!("10.0.0/8".parse::<IpNetwork>().is_err());
+    assert!(toml::from_str::<DnConfig>(r#"allowed_networks = ["nope"]"#).is_err());
+}
+
+#[test]
+fn test_dnconfig_write_allowed() {
+    let modify = |dn: &str, atype: &str| {
+        LdapOp::ModifyRequest(LdapModifyRequest {
+            dn: dn.to_string(),
+            changes: vec![LdapModify {
+                operation: LdapModifyType::Replace,
+                modification: LdapPartialAttribute {
+                    atype: atype.to_string(),
+                    vals: vec![b"value".to_vec()],
+                },
+            }],
+        })
+    };
+    let delete = LdapOp::DelRequest("uid=user,ou=people,o=example".to_string());
+
+    let dnconfig = DnConfig::default();
+    assert!(!dnconfig.write_allowed(&modify("uid=user,ou=people,o=example", "mail")));
+
+    let dnconfig = toml::from_str::<DnConfig>(
+        r#"
+write_policy = { operations = ["modify"], subtree = "ou=people,o=example", attributes = ["mail", "sshPublicKey"] }
+"#,
+    )
+    .expect("Failed to load config");
+
+    assert!(dnconfig.write_allowed(&modify("uid=user,ou=people,o=example", "mail")));
+    assert!(dnconfig.write_allowed(&modify("uid=user,OU=people,O=example", "sshpublickey")));
+    assert!(!dnconfig.write_allowed(&modify("uid=user,ou=people,o=example", "userPassword")));
+    assert!(!dnconfig.write_allowed(&modify("uid=user,ou=groups,o=example", "mail")));
+    assert!(!dnconfig.write_allowed(&delete));
+}
+
+#[test]
+fn test_dnconfig_query_templates() {
+    let dnconfig = toml::from_str::<DnConfig>(
+        r#"
+allowed_queries = [
+    ["o=example", "subtree", "(&(objectClass=posixAccount)(uid=*))"],
+    ["o=example", "subtree", "(&(objectClass=posixGroup)(gidNumber=${number}))"],
+    ["o=example", "subtree", "(|(mail=${name})(cn=${any}))"],
+    ["o=example", "subtree", "(&(objectClass=person)(!(memberOf=*)))"],
+]
+"#,
+    )
+    .expect("Failed to load config");
+
+    let allowed = |base: &str, filter: &str| {
+        let filter = ldap3_proto::parse_ldap_filter_str(filter).expect("Invalid filter");
+        dnconfig.query_allowed(
+            &canonical_dn(base),
+            &LdapSearchScope::Subtree,
+            &canonical_filter(&filter),
+        )
+    };
+
+    assert!(allowed("o=example", "(&(objectClass=posixAccount)(uid=*))"));
+    assert!(allowed(
+        "o=example",
+        "(&(objectClass=posixAccount)(uid=alice))"
+    ));
+    assert!(allowed(
+        "o=example",
+        "(&(objectclass=posixAccount)(UID=bob))"
+    ));
+    assert!(!allowed(
+        "o=example",
+        "(&(objectClass=posixAccount)(uid=a*))"
+    ));
+    assert!(!allowed("o=example", "(&(objectClass=person)(uid=alice))"));
+    assert!(!allowed(
+        "o=example",
+        "(&(objectClass=posixAccount)(uid=alice)(uid=bob))"
+    ));
+    assert!(!allowed(
+        "o=other",
+        "(&(objectClass=posixAccount)(uid=alice))"
+    ));
+
+    assert!(allowed(
+        "o=example",
+        "(&(objectClass=posixGroup)(gidNumber=1000))"
+    ));
+    assert!(!allowed(
+        "o=example",
+        "(&(objectClass=posixGroup)(gidNumber=10a))"
+    ));
+
+    assert!(allowed(
+        "o=example",
+        "(|(mail=alice@example.com)(cn=Alice+Smith))"
+    ));
+    assert!(!allowed(
+        "o=example",
+        "(|(mail=alice+smith)(cn=Alice+Smith))"
+    ));
+
+    assert!(allowed(
+        "o=example",
+        "(&(objectClass=person)(!(memberOf=*)))"
+    ));
+    assert!(!allowed(
+        "o=example",
+        "(&(objectClass=person)(!(memberOf=\"cn=x,ou=groups,o=example\")))"
+    ));
+}
+
+#[test]
+fn test_group_policy() {
+    let config = parse_config(
+        r#"
+
+[group_policy]
+groups = [
+    ["cn=admins,ou=groups,o=example", "admins"],
+    ["*,ou=groups,o=example", "readers"],
+]
+
+[policies.admins]
+[policies.readers]
+denial_message = "readers"
+
+["cn=Administrator"]
+"#,
+    );
+    assert!(config.binddn_map.contains_key("cn=Administrator"));
+    assert!(!config.binddn_map.contains_key("policies"));
+
+    let group_policy_config = config.group_policy.expect("No group policy");
+    assert_eq!(group_policy_config.attribute, "memberOf");
+    let group_policy =
+        GroupPolicy::new(&group_policy_config, &config.policies).expect("Invalid group policy");
+
+    let groups = |groups: &[&str]| groups.iter().map(|g| g.to_string()).collect::<Vec<_>>();
+    assert_eq!(
+        group_policy
+            .select(&groups(&[
+                "cn=other,o=example",
+                "CN=admins,ou=groups,o=example"
+            ]))
+            .as_deref(),
+        Some("admins")
+    );
+    assert_eq!(
+        group_policy
+            .select(&groups(&["cn=users,ou=groups,o=example"]))
+            .as_deref(),
+        Some("readers")
+    );
+    assert_eq!(group_policy.select(&groups(&["cn=other,o=example"])), None);
+    assert_eq!(
+        group_policy
+            .policy("readers")
+            .and_then(|dnconfig| dnconfig.denial_message.as_deref()),
+        Some("readers")
+    );
+
+    let dn = "uid=user,ou=people,o=example";
+    assert_eq!(group_policy.cached(dn), None);
+    group_policy.cache_policy(dn, Some("readers".to_string()));
+    assert_eq!(
+        group_policy.cached("UID=user, ou=people,o=example"),
+        Some(Some("readers".to_string()))
+    );
+
+    let sr = group_policy.search_request(dn);
+    assert_eq!(sr.base, dn);
+    assert_eq!(sr.scope, LdapSearchScope::Base);
+    assert_eq!(sr.attrs, vec!["memberOf".to_string()]);
+    let entry = LdapSearchResultEntry {
+        dn: dn.to_string(),
+        attributes: vec![LdapPartialAttribute {
+            atype: "memberof".to_string(),
+            vals: vec![b"cn=admins,ou=groups,o=example".to_vec()],
+        }],
+    };
+    assert_eq!(
+        group_policy.entry_groups(&entry),
+        groups(&["cn=admins,ou=groups,o=example"])
+    );
+
+    let group_policy_config = toml::from_str(
+        r#"
+search = { base = "ou=groups,o=example", filter = "(&(objectClass=groupOfNames)(member=${dn}))" }
+groups = [["*,ou=groups,o=example", "readers"]]
+"#,
+    )
+    .expect("Failed to load group policy");
+    let group_policy =
+        GroupPolicy::new(&group_policy_config, &config.policies).expect("Invalid group policy");
+
+    let sr = group_policy.search_request(dn);
+    assert_eq!(sr.base, "ou=groups,o=example");
+    assert_eq!(sr.scope, LdapSearchScope::Subtree);
+    assert_eq!(
+        sr.filter,
+        LdapFilter::And(vec![
+            LdapFilter::Equality("member".to_string(), dn.to_string()),
+            LdapFilter::Equality("objectclass".to_string(), "groupOfNames".to_string()),
+        ])
+    );
+    assert_eq!(group_policy.entry_groups(&entry), groups(&[dn]));
+
+    let group_policy_config = toml::from_str(
+        r#"
+groups = [["*,ou=groups,o=example", "missing"]]
+"#,
+    )
+    .expect("Failed to load group policy");
+    assert!(GroupPolicy::new(&group_policy_config, &config.policies).is_err());
+
+    // A policy is chosen after the bind, so it can't map the dn that is bound.
+    let group_policy_config = toml::from_str(
+        r#"
+groups = [["*,ou=groups,o=example", "readers"]]
+"#,
+    )
+    .expect("Failed to load group policy");
+    let mut policies = config.policies.clone();
+    let readers = policies.get_mut("readers").expect("No readers policy");
+    readers.map_to_dn = Some("cn=reader".to_string());
+    assert!(GroupPolicy::new(&group_policy_config, &policies).is_err());
+}
+
+#[test]
+fn test_dn_comparison() {
+    assert!(dn_eq(
+        "cn=Alice,ou=People,dc=example",
+        "CN=Alice, ou=People,DC=example"
+    ));
+    // Values may be case sensitive, so only attribute types are compared without case.
+    assert!(!dn_eq("cn=Alice,dc=example", "cn=alice,dc=example"));
+    assert!(dn_eq("cn=a\\,b,dc=example", "cn=a\\2cb,dc=example"));
+    assert!(dn_eq("cn=a+uid=b,dc=example", "uid=b+cn=a,dc=example"));
+    assert!(!dn_eq("cn=a\\,dc=example", "cn=a,dc=example"));
+
+    assert_eq!(
+        dn_depth_below("ou=people,dc=example", "dc=example"),
+        Some(1)
+    );
+    assert_eq!(dn_depth_below("dc=example", "dc=example"), Some(0));
+    assert_eq!(dn_depth_below("dc=example", ""), Some(1));
+    assert_eq!(dn_depth_below("cn=x\\,dc=example", "dc=example"), None);
+    assert_eq!(
+        dn_depth_below("ou=people,dc=notexample", "dc=example"),
+        None
+    );
+
+    assert_eq!(dn_parent("cn=a\\,b,dc=example"), "dc=example");
+    assert_eq!(dn_parent("dc=example"), "");
+}
+
+#[test]
+fn test_canonical_search() {
+    let canonical = |filter: &str| {
+        canonical_filter(&ldap3_proto::parse_ldap_filter_str(filter).expect("Invalid filter"))
+    };
+
+    assert_eq!(canonical("(UID=alice)"), canonical("(uid=alice)"));
+    assert_ne!(canonical("(uid=Alice)"), canonical("(uid=alice)"));
+    assert_eq!(canonical("(&(a=1)(b=2))"), canonical("(&(b=2)(a=1))"));
+    assert_eq!(canonical("(|(a=1)(b=2)(a=1))"), canonical("(|(B=2)(a=1))"));
+    assert_ne!(canonical("(&(a=1)(b=2))"), canonical("(|(a=1)(b=2))"));
+    assert_eq!(
+        canonical("(!(&(cn=x)(|(uid=b)(uid=a))))"),
+        canonical("(!(&(|(uid=a)(UID=b))(CN=x)))")
+    );
+    // Terms are sorted by attribute, so that templates sort like the filters they match.
+    assert_eq!(
+        canonical("(&(b=1)(a=*))"),
+        LdapFilter::And(vec![
+            LdapFilter::Present("a".to_string()),
+            LdapFilter::Equality("b".to_string(), "1".to_string()),
+        ])
+    );
+
+    assert_eq!(
+        canonical_attrs(&["mail".to_string(), "CN".to_string(), "cn".to_string()]),
+        vec!["cn".to_string(), "mail".to_string()]
+    );
+
+    assert_eq!(
+        canonical_dn("CN=Alice, ou=People,DC=example"),
+        "cn=Alice,ou=People,dc=example"
+    );
+    assert_eq!(
+        canonical_dn("cn=a\\2cb,dc=x"),
+        canonical_dn("cn=\"a,b\",dc=x")
+    );
+    assert_ne!(
+        canonical_dn("cn=a\\,b=c,dc=x"),
+        canonical_dn("cn=a,b=c,dc=x")
+    );
+
+    let search = |base: &str, filter: &str, attrs: &[&str]| {
+        canonical_search(LdapSearchRequest {
+            base: base.to_string(),
+            scope: LdapSearchScope::Subtree,
+            aliases: LdapDerefAliases::Never,
+            sizelimit: 0,
+            timelimit: 0,
+            typesonly: false,
+            filter: ldap3_proto::parse_ldap_filter_str(filter).expect("Invalid filter"),
+            attrs: attrs.iter().map(|attr| attr.to_string()).collect(),
+        })
+    };
+    assert_eq!(
+        search(
+            "ou=People,dc=example",
+            "(&(objectClass=person)(uid=alice))",
+            &["cn", "mail"]
+        ),
+        search(
+            "OU=People, DC=example",
+            "(&(UID=alice)(objectclass=person))",
+            &["MAIL", "cn"]
+        )
+    );
+}
+
+#[test]
+fn test_dnconfig_subtree_queries() {
+    let dnconfig = toml::from_str::<DnConfig>(
+        r#"
+allowed_queries = [
+    ["", "base", "(objectclass=*)"],
+    { subtree = "ou=people,dc=example,dc=com", filter = "(uid=*)", scopes = [["subtree", "one_level"], ["base"]] },
+]
+"#,
+    )
+    .expect("Failed to load config");
+
+    let allowed = |base: &str, scope: LdapSearchScope, filter: &str| {
+        let filter = ldap3_proto::parse_ldap_filter_str(filter).expect("Invalid filter");
+        dnconfig.query_allowed(&canonical_dn(base), &scope, &canonical_filter(&filter))
+    };
+
+    assert!(allowed("", LdapSearchScope::Base, "(objectclass=*)"));
+    assert!(allowed(
+        "OU=people,dc=example,dc=com",
+        LdapSearchScope::Subtree,
+        "(uid=alice)"
+    ));
+    assert!(allowed(
+        "uid=alice,ou=people,dc=example,dc=com",
+        LdapSearchScope::Base,
+        "(uid=alice)"
+    ));
+    assert!(allowed(
+        "cn=x,uid=alice,ou=people,dc=example,dc=com",
+        LdapSearchScope::Base,
+        "(uid=alice)"
+    ));
+    assert!(!allowed(
+        "uid=alice,ou=people,dc=example,dc=com",
+        LdapSearchScope::Subtree,
+        "(uid=alice)"
+    ));
+    assert!(!allowed(
+        "ou=groups,dc=example,dc=com",
+        LdapSearchScope::Subtree,
+        "(uid=alice)"
+    ));
+    assert!(!allowed(
+        "ou=people,dc=example,dc=com",
+        LdapSearchScope::Subtree,
+        "(cn=alice)"
+    ));
+}
+
+#[test]
+fn test_dnconfig_attributes() {
+    let attrs = |attrs: &[&str]| attrs.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+
+    let dnconfig = toml::from_str::<DnConfig>(
+        r#"
+denied_attributes = ["userPassword", "krbPrincipalKey"]
+"#,
+    )
+    .expect("Failed to load config");
+
+    assert!(dnconfig.attribute_allowed("mail"));
+    assert!(!dnconfig.attribute_allowed("userpassword"));
+    assert!(!dnconfig.attribute_allowed("userPassword;binary"));
+    assert_eq!(dnconfig.requested_attrs(&[]), attrs(&[]));
+    assert_eq!(
+        dnconfig.requested_attrs(&attrs(&["cn", "userPassword"])),
+        attrs(&["cn"])
+    );
+    assert_eq!(
+        dnconfig.requested_attrs(&attrs(&["userPassword"])),
+        attrs(&["1.1"])
+    );
+
+    let dnconfig = toml::from_str::<DnConfig>(
+        r#"
+allowed_attributes = ["uid", "mail", "userPassword"]
+denied_attributes = ["userPassword"]
+"#,
+    )
+    .expect("Failed to load config");
+
+    assert_eq!(dnconfig.requested_attrs(&[]), attrs(&["mail", "uid"]));
+    assert_eq!(
+        dnconfig.requested_attrs(&attrs(&["*", "+"])),
+        attrs(&["+", "mail", "uid"])
+    );
+    assert_eq!(
+        dnconfig.requested_attrs(&attrs(&["cn", "uid"])),
+        attrs(&["uid"])
+    );
+
+    let mut entry = LdapSearchResultEntry {
+        dn: "uid=alice,o=example".to_string(),
+        attributes: ["uid", "mail", "userPassword", "cn"]
+            .iter()
+            .map(|atype| LdapPartialAttribute {
+                atype: atype.to_string(),
+                vals: vec![b"value".to_vec()],
+            })
+            .collect(),
+    };
+    dnconfig.filter_entry(&mut entry);
+    let atypes: Vec<_> = entry.attributes.iter().map(|a| a.atype.as_str()).collect();
+    assert_eq!(atypes, vec!["uid", "mail"]);
+
+    assert!(!dnconfig.compare_allowed("uid=alice,o=example", "userPassword"));
+
+    // Filters may only test the attributes that are allowed.
+    let filter = |f: &str| ldap3_proto::parse_ldap_filter_str(f).expect("Invalid filter");
+    assert!(dnconfig.filter_allowed(&filter("(&(uid=alice)(!(mail=*)))")));
+    assert!(!dnconfig.filter_allowed(&filter("(cn=alice)")));
+    assert!(!dnconfig.filter_allowed(&filter("(userPassword={SSHA}a*)")));
+    assert!(!dnconfig.filter_allowed(&filter("(|(uid=alice)(userpassword>=a))")));
+    assert!(!dnconfig.filter_allowed(&filter("(!(userPassword=*))")));
+    assert!(!dnconfig.filter_allowed(&filter("(userPassword:caseExactMatch:=a)")));
+    // An extensible match without a type matches every attribute.
+    assert!(!dnconfig.filter_allowed(&filter("(:caseExactMatch:=a)")));
+}
+
+#[test]
+fn test_dnconfig_forced_filter() {
+    let filter = ldap3_proto::parse_ldap_filter_str("(uid=alice)").expect("Invalid filter");
+
+    let dnconfig = DnConfig::default();
+    assert_eq!(dnconfig.search_filter(filter.clone()), filter);
+
+    let dnconfig = toml::from_str::<DnConfig>(
+        r#"
+forced_filter = '(memberOf="cn=tenant-a,ou=groups,dc=example,dc=com")'
+"#,
+    )
+    .expect("Failed to load config");
+
+    assert_eq!(
+        dnconfig.search_filter(filter.clone()),
+        LdapFilter::And(vec![
+            LdapFilter::Equality(
+                "memberof".to_string(),
+                "cn=tenant-a,ou=groups,dc=example,dc=com".to_string()
+            ),
+            filter
+        ])
+    );
+
+    // Compares would bypass the filter, so only allowed compares may be made.
+    assert!(!dnconfig.compare_allowed("uid=alice,ou=people,dc=example,dc=com", "uid"));
+
+    let dnconfig = toml::from_str::<DnConfig>(
+        r#"
+forced_filter = '(memberOf="cn=tenant-a,ou=groups,dc=example,dc=com")'
+allowed_compares = [
+    ["*,ou=groups,dc=example,dc=com", "member"],
+]
+"#,
+    )
+    .expect("Failed to load config");
+
+    assert!(dnconfig.compare_allowed("cn=tenant-a,ou=groups,dc=example,dc=com", "member"));
+    assert!(!dnconfig.compare_allowed("uid=alice,ou=people,dc=example,dc=com", "uid"));
+}
+
 #[test]
 fn test_cachedvalue() {
     let cv = CachedValue {
         valid_until: Instant::now() + Duration::from_secs(60),
+        generation: 0,
         entries: Vec::with_capacity(5),
         result: LdapResult {
             code: ldap3_proto::LdapResultCode::Busy,
@@ -31,5 +904,1405 @@ fn test_cachedvalue() {
         },
         ctrl: Vec::with_capacity(5),
     };
-    assert_eq!(cv.size(), 144);
+    assert_eq!(cv.size(), 152);
+}
+
+#[test]
+fn test_cache_snapshot() {
+    let cache: ARCache<SearchCacheKey, CachedValue> = ARCacheBuilder::new()
+        .set_size(1024, 0)
+        .build()
+        .expect("Failed to build cache");
+
+    let path = std::env::temp_dir().join(format!("ldap-proxy-snapshot-{}", std::process::id()));
+    let write_log = WriteLog::new(Duration::from_secs(60));
+
+    assert!(load_cache(&cache, &path).is_err());
+    assert_eq!(
+        save_cache(&cache, &write_log, &path).expect("Failed to save cache"),
+        0
+    );
+    assert_eq!(load_cache(&cache, &path).expect("Failed to load cache"), 0);
+
+    std::fs::write(&path, r#"{"version":0,"values":[]}"#).expect("Failed to write snapshot");
+    assert!(load_cache(&cache, &path).is_err());
+    std::fs::write(&path, "not a snapshot").expect("Failed to write snapshot");
+    assert!(load_cache(&cache, &path).is_err());
+
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn test_reloadable_cert_resolver() {
+    let dir = std::env::temp_dir().join(format!("ldap-proxy-tls-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("Failed to create directory");
+    let tls_chain = dir.join("chain.pem");
+    let tls_key = dir.join("key.pem");
+    std::fs::copy(test_data("chain.pem"), &tls_chain).expect("Failed to copy chain");
+    std::fs::copy(test_data("key.pem"), &tls_key).expect("Failed to copy key");
+
+    let provider = rustls::ServerConfig::builder().crypto_provider().clone();
+    let resolver =
+        ReloadableCertResolver::new(&tls_chain, &tls_key, provider).expect("Invalid certificate");
+    let certified_key = || resolver.certified_key().expect("No certificate");
+    let loaded = certified_key();
+
+    assert!(matches!(resolver.reload_if_modified(), Ok(false)));
+
+    // A chain and key that aren't a pair are refused, and the current ones are kept.
+    assert!(matches!(
+        resolver.reload(&tls_chain, &test_data("other_key.pem")),
+        Err(TlsError::KeyPair)
+    ));
+    assert!(Arc::ptr_eq(&certified_key(), &loaded));
+    assert!(matches!(resolver.reload_if_modified(), Ok(false)));
+
+    // Once the files are replaced, they are loaded again.
+    std::fs::copy(test_data("other_chain.pem"), &tls_chain).expect("Failed to copy chain");
+    std::fs::copy(test_data("other_key.pem"), &tls_key).expect("Failed to copy key");
+    let modified = std::time::SystemTime::now() + Duration::from_secs(10);
+    for path in [&tls_chain, &tls_key] {
+        std::fs::File::options()
+            .write(true)
+            .open(path)
+            .and_then(|file| file.set_modified(modified))
+            .expect("Failed to set modified time");
+    }
+    assert!(matches!(resolver.reload_if_modified(), Ok(true)));
+    assert_ne!(certified_key().cert, loaded.cert);
+    assert!(matches!(resolver.reload_if_modified(), Ok(false)));
+
+    std::fs::remove_dir_all(&dir).expect("Failed to remove directory");
+}
+
+#[tokio::test]
+async fn test_bind_pool_min_idle() {
+    let ldap = FakeLdap::start().await;
+    let pool = BindPool::new(
+        "cn=pooled".to_string(),
+        "secret".to_string(),
+        ldap.upstreams(),
+        3,
+        5,
+        Duration::from_secs(300),
+    );
+
+    pool.maintain().await;
+    assert_eq!(pool.idle_len(), 3);
+    assert_eq!(ldap.connections(), 3);
+
+    // The pool is already at its minimum.
+    pool.maintain().await;
+    assert_eq!(pool.idle_len(), 3);
+    assert_eq!(ldap.connections(), 3);
+
+    // Binds take an idle connection, and it is returned afterwards.
+    let lbr = LdapBindRequest {
+        dn: "cn=pooled".to_string(),
+        cred: LdapBindCred::Simple("secret".to_string()),
+    };
+    let (client, bind_resp, _) = pool
+        .bind(lbr, Vec::with_capacity(0))
+        .await
+        .expect("Failed to bind");
+    assert_eq!(bind_resp.res.code, LdapResultCode::Success);
+    assert_eq!(pool.idle_len(), 2);
+    assert_eq!(ldap.connections(), 3);
+    pool.checkin(client);
+    assert_eq!(pool.idle_len(), 3);
+}
+
+fn test_upstream_set(servers: Vec<UpstreamServer>) -> UpstreamSet {
+    let tls_config = rustls::ClientConfig::builder()
+        .with_root_certificates(rustls::RootCertStore::empty())
+        .with_no_client_auth();
+
+    UpstreamSet::new(
+        servers,
+        TlsConnector::from(Arc::new(tls_config)),
+        None,
+        1,
+        Duration::from_secs(30),
+        Duration::from_secs(5),
+    )
+}
+
+fn test_upstream_server(name: &str, priority: u32, weight: u32) -> UpstreamServer {
+    UpstreamServer::new(
+        name.to_string(),
+        LdapUpstream::Plaintext {
+            addrs: Vec::with_capacity(0),
+        },
+        priority,
+        weight,
+    )
+}
+
+#[test]
+fn test_upstream_candidates() {
+    let upstreams = test_upstream_set(vec![
+        test_upstream_server("dr", 1, 1),
+        test_upstream_server("a", 0, 1),
+        test_upstream_server("b", 0, 3),
+    ]);
+
+    let names = |upstreams: &UpstreamSet| -> Vec<String> {
+        upstreams
+            .candidates()
+            .iter()
+            .map(|server| server.name.clone())
+            .collect()
+    };
+
+    // Servers of the same priority share connections by weight, before the next priority.
+    let mut firsts = Vec::new();
+    for _ in 0..4 {
+        let candidates = names(&upstreams);
+        assert_eq!(candidates.len(), 3);
+        assert_eq!(candidates[2], "dr");
+        firsts.push(candidates[0].clone());
+    }
+    assert_eq!(firsts.iter().filter(|name| *name == "a").count(), 1);
+    assert_eq!(firsts.iter().filter(|name| *name == "b").count(), 3);
+
+    // Servers that are down are skipped.
+    for server in upstreams.candidates() {
+        if server.priority == 0 {
+            upstreams.record_failure(server);
+        }
+    }
+    assert_eq!(names(&upstreams), vec!["dr".to_string()]);
+
+    // Once every server is down, they are all tried again in order.
+    for server in upstreams.candidates() {
+        upstreams.record_failure(server);
+    }
+    let candidates = names(&upstreams);
+    assert_eq!(candidates.len(), 3);
+    assert_eq!(candidates[2], "dr");
+
+    // A server that recovers is used alone again.
+    for server in upstreams.candidates() {
+        if server.name == "a" {
+            upstreams.record_success(server);
+        }
+    }
+    assert_eq!(names(&upstreams), vec!["a".to_string()]);
+}
+
+/// How long the fake ldap server takes to answer searches based at an ou=slow entry.
+const SLOW_SEARCH: Duration = Duration::from_millis(300);
+
+/// A small ldap server for the tests that need one. Binds succeed unless the password
+/// is "wrong". Searches return one entry below the base, which holds the dn that the
+/// connection is bound as. A base of an ou=empty entry has none, an ou=many entry has
+/// five whatever the size limit, and an ou=stalled entry has one and the search is never
+/// done. Compares are true when the value is that dn, and modifies
+/// succeed. Requests are answered concurrently. Searches based at an ou=slow entry, and
+/// modifies of entries below one, are answered after `SLOW_SEARCH`.
+struct FakeLdap {
+    addr: SocketAddr,
+    connections: Arc<AtomicUsize>,
+    searches: Arc<Mutex<Vec<LdapSearchRequest>>>,
+    abandons: Arc<AtomicUsize>,
+    tasks: Arc<Mutex<Vec<AbortHandle>>>,
+}
+
+impl FakeLdap {
+    async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Failed to bind fake ldap server");
+        let addr = listener.local_addr().expect("Failed to get address");
+
+        let connections = Arc::new(AtomicUsize::new(0));
+        let searches = Arc::new(Mutex::new(Vec::new()));
+        let abandons = Arc::new(AtomicUsize::new(0));
+        let tasks = Arc::new(Mutex::new(Vec::new()));
+
+        let accept = {
+            let connections = connections.clone();
+            let searches = searches.clone();
+            let abandons = abandons.clone();
+            let tasks = tasks.clone();
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    connections.fetch_add(1, Ordering::Relaxed);
+                    let task = tokio::spawn(fake_ldap_connection(
+                        stream,
+                        searches.clone(),
+                        abandons.clone(),
+                        tasks.clone(),
+                    ));
+                    track(&tasks, task.abort_handle());
+                }
+            })
+        };
+        track(&tasks, accept.abort_handle());
+
+        FakeLdap {
+            addr,
+            connections,
+            searches,
+            abandons,
+            tasks,
+        }
+    }
+
+    /// Stop accepting connections, and close those that are open.
+    fn stop(&self) {
+        for task in self.tasks.lock().expect("Failed to lock tasks").drain(..) {
+            task.abort();
+        }
+    }
+
+    fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    /// The searches that have been received, in order.
+    fn searches(&self) -> Vec<LdapSearchRequest> {
+        self.searches
+            .lock()
+            .expect("Failed to lock searches")
+            .clone()
+    }
+
+    fn abandons(&self) -> usize {
+        self.abandons.load(Ordering::Relaxed)
+    }
+
+    fn upstreams(&self) -> Arc<UpstreamSet> {
+        let server = UpstreamServer::new(
+            self.addr.to_string(),
+            LdapUpstream::Plaintext {
+                addrs: vec![self.addr],
+            },
+            0,
+            1,
+        );
+        Arc::new(test_upstream_set(vec![server]))
+    }
+}
+
+fn track(tasks: &Mutex<Vec<AbortHandle>>, task: AbortHandle) {
+    tasks.lock().expect("Failed to lock tasks").push(task);
+}
+
+async fn fake_ldap_connection<S: AsyncRead + AsyncWrite + Send + 'static>(
+    stream: S,
+    searches: Arc<Mutex<Vec<LdapSearchRequest>>>,
+    abandons: Arc<AtomicUsize>,
+    tasks: Arc<Mutex<Vec<AbortHandle>>>,
+) {
+    let (r, w) = tokio::io::split(stream);
+    let mut r = FramedRead::new(r, LdapCodec::new(None, None));
+    let mut w = FramedWrite::new(w, LdapCodec::new(None, None));
+
+    let (tx, mut rx) = mpsc::unbounded_channel::<LdapMsg>();
+    let writer = tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            if w.send(msg).await.is_err() {
+                break;
+            }
+        }
+    });
+    track(&tasks, writer.abort_handle());
+
+    let reply = move |msgid: i32, ops: Vec<LdapOp>| {
+        for op in ops {
+            let _ = tx.send(LdapMsg {
+                msgid,
+                op,
+                ctrl: vec![],
+            });
+        }
+    };
+
+    let mut bound_as = String::new();
+
+    while let Some(Ok(LdapMsg { msgid, op, ctrl: _ })) = r.next().await {
+        match op {
+            LdapOp::BindRequest(lbr) => {
+                let code = match lbr.cred {
+                    LdapBindCred::Simple(pw) if pw == "wrong" => LdapResultCode::InvalidCredentials,
+                    _ => {
+                        bound_as = lbr.dn;
+                        LdapResultCode::Success
+                    }
+                };
+                reply(
+                    msgid,
+                    vec![LdapOp::BindResponse(LdapBindResponse {
+                        res: test_result(code),
+                        saslcreds: None,
+                    })],
+                );
+            }
+            LdapOp::SearchRequest(sr) => {
+                searches
+                    .lock()
+                    .expect("Failed to lock searches")
+                    .push(sr.clone());
+                let entry = |cn: &str| {
+                    LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                        dn: format!("cn={},{}", cn, sr.base),
+                        attributes: vec![LdapPartialAttribute {
+                            atype: "boundAs".to_string(),
+                            vals: vec![bound_as.clone().into_bytes()],
+                        }],
+                    })
+                };
+                let done = LdapOp::SearchResultDone(test_result(LdapResultCode::Success));
+                let ops = if sr.base.starts_with("ou=empty,") {
+                    vec![done]
+                } else if sr.base.starts_with("ou=many,") {
+                    // The limits of the search are ignored.
+                    (0..5)
+                        .map(|i| entry(&format!("result{}", i)))
+                        .chain([done])
+                        .collect()
+                } else if sr.base.starts_with("ou=stalled,") {
+                    vec![entry("result")]
+                } else {
+                    vec![entry("result"), done]
+                };
+                if sr.base.starts_with("ou=slow,") {
+                    let reply = reply.clone();
+                    tokio::spawn(async move {
+                        tokio::time::sleep(SLOW_SEARCH).await;
+                        reply(msgid, ops);
+                    });
+                } else {
+                    reply(msgid, ops);
+                }
+            }
+            LdapOp::CompareRequest(cr) => {
+                let code = if cr.val == bound_as.as_bytes() {
+                    LdapResultCode::CompareTrue
+                } else {
+                    LdapResultCode::CompareFalse
+                };
+                reply(msgid, vec![LdapOp::CompareResult(test_result(code))]);
+            }
+            LdapOp::ModifyRequest(mr) => {
+                let ops = vec![LdapOp::ModifyResponse(test_result(LdapResultCode::Success))];
+                if mr.dn.contains(",ou=slow,") {
+                    let reply = reply.clone();
+                    tokio::spawn(async move {
+                        tokio::time::sleep(SLOW_SEARCH).await;
+                        reply(msgid, ops);
+                    });
+                } else {
+                    reply(msgid, ops);
+                }
+            }
+            LdapOp::AbandonRequest(_) => {
+                abandons.fetch_add(1, Ordering::Relaxed);
+            }
+            LdapOp::UnbindRequest => break,
+            _ => {}
+        }
+    }
+}
+
+/// How a fake ldap server answers a StartTLS request.
+#[derive(Debug, Clone, Copy)]
+enum StartTlsReply {
+    Refuse,
+    /// Agree, but send another message before the handshake.
+    Trailing,
+    /// Agree, and establish TLS with the test certificate.
+    Accept,
+}
+
+/// A fake ldap server that answers StartTLS on one connection, and then serves it as
+/// `FakeLdap` does if TLS is established.
+async fn fake_starttls_ldap(reply: StartTlsReply) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Failed to bind");
+    let addr = listener.local_addr().expect("Failed to get address");
+
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.expect("Failed to accept");
+        let mut framed = Framed::new(stream, LdapCodec::new(None, None));
+        let msgid = match framed.next().await {
+            Some(Ok(LdapMsg {
+                msgid,
+                op: LdapOp::ExtendedRequest(ler),
+                ..
+            })) if ler.name == OID_STARTTLS => msgid,
+            msg => panic!("Unexpected request {:?}", msg),
+        };
+
+        let code = match reply {
+            StartTlsReply::Refuse => LdapResultCode::UnwillingToPerform,
+            StartTlsReply::Trailing | StartTlsReply::Accept => LdapResultCode::Success,
+        };
+        let response = LdapMsg {
+            msgid,
+            op: LdapOp::ExtendedResponse(LdapExtendedResponse {
+                res: test_result(code),
+                name: Some(OID_STARTTLS.to_string()),
+                value: None,
+            }),
+            ctrl: vec![],
+        };
+        framed.feed(response.clone()).await.expect("Failed to send");
+        if let StartTlsReply::Trailing = reply {
+            framed.feed(response).await.expect("Failed to send");
+        }
+        framed.flush().await.expect("Failed to send");
+
+        if let StartTlsReply::Accept = reply {
+            let (tls_acceptor, _) = test_tls();
+            let stream = tls_acceptor
+                .accept(framed.into_inner())
+                .await
+                .expect("Failed to establish TLS");
+            fake_ldap_connection(stream, Arc::default(), Arc::default(), Arc::default()).await;
+        }
+    });
+
+    addr
+}
+
+/// The app state of a config, with the ldap servers of `upstreams`.
+fn test_app_state(config: &Config, upstreams: Arc<UpstreamSet>) -> Arc<AppState> {
+    let cache = ARCacheBuilder::new()
+        .set_size(config.cache_bytes, 0)
+        .build()
+        .expect("Failed to build cache");
+
+    Arc::new(AppState {
+        upstreams,
+        bind_pools: BTreeMap::new(),
+        binddn_map: BindMap::new(&config.binddn_map).expect("Invalid bind map"),
+        group_policy: config.group_policy.as_ref().map(|group_policy| {
+            GroupPolicy::new(group_policy, &config.policies).expect("Invalid group policy")
+        }),
+        cache: Arc::new(cache),
+        cache_entry_timeout: Duration::from_secs(config.cache_entry_timeout),
+        cache_negative_timeout: Duration::from_secs(config.cache_negative_timeout),
+        cache_max_entry_bytes: config.cache_max_entry_bytes,
+        cache_compares: config.cache_compares,
+        cache_result_codes: config.cache_result_codes.clone(),
+        cache_stale_while_revalidate: Duration::from_secs(config.cache_stale_while_revalidate),
+        cache_stale_if_error: Duration::from_secs(config.cache_stale_if_error),
+        cache_refreshing: Default::default(),
+        cache_stats: Arc::default(),
+        write_log: Arc::new(WriteLog::new(Duration::from_secs(
+            config.cache_entry_timeout,
+        ))),
+        max_incoming_ber_size: config.max_incoming_ber_size,
+        max_proxy_ber_size: config.max_proxy_ber_size,
+        allow_all_bind_dns: config.allow_all_bind_dns,
+        remote_ip_addr_info: config.remote_ip_addr_info,
+        starttls_required: config.starttls_required,
+        denial_response: config.denial_response,
+        bind_denial_response: config.bind_denial_response,
+        denial_message: config.denial_message.clone(),
+    })
+}
+
+/// A client of a session of the proxy, connected in memory.
+struct TestClient<S = DuplexStream> {
+    framed: Framed<S, LdapCodec>,
+}
+
+impl TestClient {
+    fn connect(app_state: &Arc<AppState>) -> Self {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let client_address = SocketAddr::from(([127, 0, 0, 1], 40000));
+        let mut session = ClientSession::new(client_address, None, app_state.clone());
+
+        tokio::spawn(async move {
+            let (r, w) = tokio::io::split(server);
+            let mut r = FramedRead::new(r, LdapCodec::new(None, None));
+            let mut w = FramedWrite::new(w, LdapCodec::new(None, None));
+            session.process(&mut r, &mut w, true).await
+        });
+
+        TestClient {
+            framed: Framed::new(client, LdapCodec::new(None, None)),
+        }
+    }
+
+    /// Connect and bind as the dn, with msgid 1.
+    async fn bound(app_state: &Arc<AppState>, dn: &str) -> Self {
+        let mut client = TestClient::connect(app_state);
+        assert_eq!(client.bind(1, dn, "secret").await, LdapResultCode::Success);
+        client
+    }
+
+    /// Connect without TLS, as to the StartTLS listener.
+    fn connect_plain(app_state: &Arc<AppState>) -> Self {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let client_address = SocketAddr::from(([127, 0, 0, 1], 40000));
+        let (tls_acceptor, _) = test_tls();
+
+        tokio::spawn(starttls_client_process(
+            server,
+            client_address,
+            None,
+            tls_acceptor,
+            app_state.clone(),
+        ));
+
+        TestClient {
+            framed: Framed::new(client, LdapCodec::new(None, None)),
+        }
+    }
+
+    /// Request StartTLS with this msgid, and establish TLS.
+    async fn starttls(mut self, msgid: i32) -> TestClient<TlsStream<DuplexStream>> {
+        assert_eq!(self.starttls_request(msgid).await, LdapResultCode::Success);
+        let (_, tls_connector) = test_tls();
+        let server_name = ServerName::try_from("localhost").expect("Invalid server name");
+        let stream = tls_connector
+            .connect(server_name, self.framed.into_inner())
+            .await
+            .expect("Failed to establish TLS");
+
+        TestClient {
+            framed: Framed::new(stream, LdapCodec::new(None, None)),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> TestClient<S> {
+    async fn send(&mut self, msgid: i32, op: LdapOp) {
+        self.framed
+            .send(LdapMsg {
+                msgid,
+                op,
+                ctrl: vec![],
+            })
+            .await
+            .expect("Failed to send request");
+    }
+
+    async fn recv(&mut self) -> LdapMsg {
+        tokio::time::timeout(Duration::from_secs(5), self.framed.next())
+            .await
+            .expect("Timed out waiting for a response")
+            .expect("The session closed")
+            .expect("Invalid response")
+    }
+
+    async fn bind(&mut self, msgid: i32, dn: &str, pw: &str) -> LdapResultCode {
+        self.send(
+            msgid,
+            LdapOp::BindRequest(LdapBindRequest {
+                dn: dn.to_string(),
+                cred: LdapBindCred::Simple(pw.to_string()),
+            }),
+        )
+        .await;
+        match self.recv().await {
+            LdapMsg {
+                msgid: resp_msgid,
+                op: LdapOp::BindResponse(resp),
+                ..
+            } if resp_msgid == msgid => resp.res.code,
+            msg => panic!("Unexpected response {:?}", msg),
+        }
+    }
+
+    /// Request StartTLS with this msgid, returning the result code.
+    async fn starttls_request(&mut self, msgid: i32) -> LdapResultCode {
+        self.send(
+            msgid,
+            LdapOp::ExtendedRequest(LdapExtendedRequest {
+                name: OID_STARTTLS.to_string(),
+                value: None,
+            }),
+        )
+        .await;
+        match self.recv().await {
+            LdapMsg {
+                msgid: resp_msgid,
+                op: LdapOp::ExtendedResponse(resp),
+                ..
+            } if resp_msgid == msgid => resp.res.code,
+            msg => panic!("Unexpected response {:?}", msg),
+        }
+    }
+
+    /// Search, returning the entries and the result code.
+    async fn search(
+        &mut self,
+        msgid: i32,
+        sr: LdapSearchRequest,
+    ) -> (Vec<LdapSearchResultEntry>, LdapResultCode) {
+        self.send(msgid, LdapOp::SearchRequest(sr)).await;
+        let mut entries = Vec::new();
+        loop {
+            match self.recv().await {
+                LdapMsg {
+                    msgid: resp_msgid,
+                    op: LdapOp::SearchResultEntry(entry),
+                    ..
+                } if resp_msgid == msgid => entries.push(entry),
+                LdapMsg {
+                    msgid: resp_msgid,
+                    op: LdapOp::SearchResultDone(res),
+                    ..
+                } if resp_msgid == msgid => return (entries, res.code),
+                msg => panic!("Unexpected response {:?}", msg),
+            }
+        }
+    }
+}
+
+/// The path of a file in tests/data.
+fn test_data(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/data")
+        .join(name)
+}
+
+/// An acceptor with the test certificate for localhost, and a connector that trusts it.
+fn test_tls() -> (TlsAcceptor, TlsConnector) {
+    let chain = CertificateDer::pem_file_iter(test_data("chain.pem"))
+        .expect("Failed to read certificate chain")
+        .collect::<Result<Vec<_>, _>>()
+        .expect("Invalid certificate chain");
+    let key = PrivateKeyDer::from_pem_file(test_data("key.pem")).expect("Invalid key");
+    let server_config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(chain, key)
+        .expect("Invalid certificate");
+
+    let mut root_cert_store = rustls::RootCertStore::empty();
+    root_cert_store
+        .add(CertificateDer::from_pem_file(test_data("ca.pem")).expect("Invalid certificate"))
+        .expect("Invalid certificate");
+    let client_config = rustls::ClientConfig::builder()
+        .with_root_certificates(root_cert_store)
+        .with_no_client_auth();
+
+    (
+        TlsAcceptor::from(Arc::new(server_config)),
+        TlsConnector::from(Arc::new(client_config)),
+    )
+}
+
+/// A search of the subtree of the base for every entry.
+fn test_search(base: &str) -> LdapSearchRequest {
+    LdapSearchRequest {
+        base: base.to_string(),
+        scope: LdapSearchScope::Subtree,
+        aliases: LdapDerefAliases::Never,
+        sizelimit: 0,
+        timelimit: 0,
+        typesonly: false,
+        filter: LdapFilter::Present("objectClass".to_string()),
+        attrs: vec![],
+    }
+}
+
+#[tokio::test]
+async fn test_starttls() {
+    let ldap = FakeLdap::start().await;
+    let config = parse_config("allow_all_bind_dns = true\n");
+    let app_state = test_app_state(&config, ldap.upstreams());
+    let mut client = TestClient::connect_plain(&app_state);
+
+    // Operations are refused until TLS is active.
+    assert_eq!(
+        client.bind(1, "cn=user,o=example", "secret").await,
+        LdapResultCode::ConfidentialityRequired
+    );
+    let (entries, code) = client.search(2, test_search("o=example")).await;
+    assert_eq!(code, LdapResultCode::ConfidentialityRequired);
+    assert!(entries.is_empty());
+    assert!(ldap.searches().is_empty());
+
+    let mut client = client.starttls(3).await;
+
+    // StartTLS can only be requested once.
+    assert_eq!(
+        client.starttls_request(4).await,
+        LdapResultCode::OperationsError
+    );
+    assert_eq!(
+        client.bind(5, "cn=user,o=example", "secret").await,
+        LdapResultCode::Success
+    );
+    let (entries, code) = client.search(6, test_search("o=example")).await;
+    assert_eq!(code, LdapResultCode::Success);
+    assert_eq!(entries.len(), 1);
+}
+
+#[tokio::test]
+async fn test_starttls_buffered_data() {
+    let config = parse_config("allow_all_bind_dns = true\n");
+    let app_state = test_app_state(&config, FakeLdap::start().await.upstreams());
+    let mut client = TestClient::connect_plain(&app_state);
+
+    // A request sent before the StartTLS response would be read as part of the handshake.
+    for (msgid, op) in [
+        (
+            1,
+            LdapOp::ExtendedRequest(LdapExtendedRequest {
+                name: OID_STARTTLS.to_string(),
+                value: None,
+            }),
+        ),
+        (2, LdapOp::SearchRequest(test_search("o=example"))),
+    ] {
+        client
+            .framed
+            .feed(LdapMsg {
+                msgid,
+                op,
+                ctrl: vec![],
+            })
+            .await
+            .expect("Failed to send request");
+    }
+    client
+        .framed
+        .flush()
+        .await
+        .expect("Failed to send requests");
+
+    match client.recv().await.op {
+        LdapOp::ExtendedResponse(resp) => assert_eq!(resp.res.code, LdapResultCode::Success),
+        op => panic!("Unexpected response {:?}", op),
+    }
+    let next = tokio::time::timeout(Duration::from_secs(5), client.framed.next())
+        .await
+        .expect("Timed out waiting for the session to close");
+    assert!(next.is_none());
+}
+
+#[tokio::test]
+async fn test_upstream_starttls() {
+    let (_, tls_connector) = test_tls();
+
+    let connect = |addr: SocketAddr| {
+        let tls_connector = tls_connector.clone();
+        async move {
+            let upstream = LdapUpstream::StartTls {
+                addrs: vec![addr],
+                tls_hostname: ServerName::try_from("localhost").expect("Invalid server name"),
+            };
+            BasicLdapClient::build(&upstream, &tls_connector, None, Duration::from_secs(5)).await
+        }
+    };
+
+    let res = connect(fake_starttls_ldap(StartTlsReply::Refuse).await).await;
+    assert!(matches!(res, Err(LdapError::TlsError)));
+
+    // Data sent before the handshake would be read as part of it.
+    let res = connect(fake_starttls_ldap(StartTlsReply::Trailing).await).await;
+    assert!(matches!(res, Err(LdapError::InvalidProtocolState)));
+
+    let client = connect(fake_starttls_ldap(StartTlsReply::Accept).await)
+        .await
+        .expect("Failed to connect");
+    let (bind_resp, _) = client
+        .bind(
+            LdapBindRequest {
+                dn: "cn=user,o=example".to_string(),
+                cred: LdapBindCred::Simple("secret".to_string()),
+            },
+            Vec::with_capacity(0),
+        )
+        .await
+        .expect("Failed to bind");
+    assert_eq!(bind_resp.res.code, LdapResultCode::Success);
+}
+
+#[test]
+fn test_build_upstream() {
+    let url = |url: &str| Url::parse(url).expect("Invalid url");
+
+    // The socket path of an ldapi url is percent encoded.
+    match build_upstream(&url("ldapi://%2Fvar%2Frun%2Fslapd%2Fldapi"), false) {
+        Ok(LdapUpstream::Ldapi { path }) => assert_eq!(path, Path::new("/var/run/slapd/ldapi")),
+        upstream => panic!("Unexpected upstream {:?}", upstream),
+    }
+    assert!(matches!(
+        build_upstream(&url("ldap://127.0.0.1:3389"), false),
+        Ok(LdapUpstream::StartTls { .. })
+    ));
+    assert!(matches!(
+        build_upstream(&url("ldap://127.0.0.1:3389"), true),
+        Ok(LdapUpstream::Plaintext { .. })
+    ));
+    assert!(matches!(
+        build_upstream(&url("ldaps://127.0.0.1"), false),
+        Ok(LdapUpstream::Ldaps { addrs, .. }) if addrs[0].port() == 636
+    ));
+    assert!(build_upstream(&url("http://127.0.0.1"), false).is_err());
+}
+
+#[tokio::test]
+async fn test_pipelined_operations() {
+    let ldap = FakeLdap::start().await;
+    let config = parse_config("allow_all_bind_dns = true\n");
+    let app_state = test_app_state(&config, ldap.upstreams());
+
+    let mut client = TestClient::bound(&app_state, "cn=user,o=example").await;
+
+    // The slow search is sent first, but its response arrives last, with its own msgid.
+    client
+        .send(2, LdapOp::SearchRequest(test_search("ou=slow,o=example")))
+        .await;
+    client
+        .send(3, LdapOp::SearchRequest(test_search("ou=fast,o=example")))
+        .await;
+
+    let mut responses = Vec::new();
+    while responses.len() < 4 {
+        let msg = client.recv().await;
+        let base = match &msg.op {
+            LdapOp::SearchResultEntry(entry) => entry.dn.clone(),
+            LdapOp::SearchResultDone(res) => {
+                assert_eq!(res.code, LdapResultCode::Success);
+                "done".to_string()
+            }
+            op => panic!("Unexpected response {:?}", op),
+        };
+        responses.push((msg.msgid, base));
+    }
+    assert_eq!(
+        responses,
+        vec![
+            (3, "cn=result,ou=fast,o=example".to_string()),
+            (3, "done".to_string()),
+            (2, "cn=result,ou=slow,o=example".to_string()),
+            (2, "done".to_string()),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_abandon() {
+    let ldap = FakeLdap::start().await;
+    let config = parse_config("allow_all_bind_dns = true\n");
+    let app_state = test_app_state(&config, ldap.upstreams());
+
+    let mut client = TestClient::bound(&app_state, "cn=user,o=example").await;
+
+    client
+        .send(2, LdapOp::SearchRequest(test_search("ou=slow,o=example")))
+        .await;
+    // Wait for the search to reach the ldap server before it is abandoned.
+    while ldap.searches().is_empty() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    client.send(3, LdapOp::AbandonRequest(2)).await;
+
+    // The abandoned search is never answered, and is abandoned at the ldap server.
+    let (entries, code) = client.search(4, test_search("ou=fast,o=example")).await;
+    assert_eq!(code, LdapResultCode::Success);
+    assert_eq!(entries.len(), 1);
+    tokio::time::sleep(SLOW_SEARCH * 2).await;
+    assert_eq!(ldap.abandons(), 1);
+
+    let (entries, code) = client.search(5, test_search("ou=fast,o=example")).await;
+    assert_eq!(code, LdapResultCode::Success);
+    assert_eq!(entries.len(), 1);
+}
+
+#[tokio::test]
+async fn test_abandon_write() {
+    let ldap = FakeLdap::start().await;
+    let config = parse_config(
+        r#"
+["cn=writer"]
+write_policy = { operations = ["modify"], subtree = "o=example", attributes = ["mail"] }
+"#,
+    );
+    let app_state = test_app_state(&config, ldap.upstreams());
+    let mut client = TestClient::bound(&app_state, "cn=writer").await;
+
+    let (_, code) = client.search(2, test_search("ou=slow,o=example")).await;
+    assert_eq!(code, LdapResultCode::Success);
+
+    client
+        .send(
+            3,
+            LdapOp::ModifyRequest(LdapModifyRequest {
+                dn: "uid=alice,ou=slow,o=example".to_string(),
+                changes: vec![LdapModify {
+                    operation: LdapModifyType::Replace,
+                    modification: LdapPartialAttribute {
+                        atype: "mail".to_string(),
+                        vals: vec![b"alice@example.com".to_vec()],
+                    },
+                }],
+            }),
+        )
+        .await;
+    client.send(4, LdapOp::AbandonRequest(3)).await;
+
+    // The write may already have been made, so it isn't abandoned, and the results it
+    // could have changed are searched for again.
+    let msg = client.recv().await;
+    assert_eq!(msg.msgid, 3);
+    match msg.op {
+        LdapOp::ModifyResponse(res) => assert_eq!(res.code, LdapResultCode::Success),
+        op => panic!("Unexpected response {:?}", op),
+    }
+    assert_eq!(ldap.abandons(), 0);
+    let (_, code) = client.search(5, test_search("ou=slow,o=example")).await;
+    assert_eq!(code, LdapResultCode::Success);
+    assert_eq!(ldap.searches().len(), 2);
+}
+
+#[tokio::test]
+async fn test_search_limits_enforced() {
+    let ldap = FakeLdap::start().await;
+    let config = parse_config(
+        r#"
+["cn=app"]
+max_size_limit = 2
+max_time_limit = 1
+"#,
+    );
+    let app_state = test_app_state(&config, ldap.upstreams());
+    let mut client = TestClient::bound(&app_state, "cn=app").await;
+
+    // The ldap server ignores the limits, so the proxy enforces them, sending the
+    // entries found until the limit was reached.
+    let (entries, code) = client.search(2, test_search("ou=many,o=example")).await;
+    assert_eq!(code, LdapResultCode::SizeLimitExceeded);
+    assert_eq!(entries.len(), 2);
+    assert_eq!(ldap.searches()[0].sizelimit, 2);
+
+    let started = Instant::now();
+    let (entries, code) = client.search(3, test_search("ou=stalled,o=example")).await;
+    assert_eq!(code, LdapResultCode::TimeLimitExceeded);
+    assert_eq!(entries.len(), 1);
+    assert!(started.elapsed() < Duration::from_secs(2));
+    assert_eq!(ldap.searches()[1].timelimit, 1);
+}
+
+#[tokio::test]
+async fn test_search_sent_as_made() {
+    let ldap = FakeLdap::start().await;
+    let config = parse_config("allow_all_bind_dns = true\n");
+    let app_state = test_app_state(&config, ldap.upstreams());
+    let mut client = TestClient::bound(&app_state, "cn=user,o=example").await;
+
+    let search = |base: &str, attrs: &[&str]| LdapSearchRequest {
+        attrs: attrs.iter().map(|attr| attr.to_string()).collect(),
+        ..test_search(base)
+    };
+
+    let (_, code) = client
+        .search(2, search("OU=People, o=Example", &["CN", "mail"]))
+        .await;
+    assert_eq!(code, LdapResultCode::Success);
+    let searches = ldap.searches();
+    assert_eq!(searches.len(), 1);
+    assert_eq!(searches[0].base, "OU=People, o=Example");
+    assert_eq!(
+        searches[0].attrs,
+        vec!["CN".to_string(), "mail".to_string()]
+    );
+
+    // The same search in another form is answered from the cache.
+    let (entries, code) = client
+        .search(3, search("ou=People,O=Example", &["mail", "cn"]))
+        .await;
+    assert_eq!(code, LdapResultCode::Success);
+    assert_eq!(entries.len(), 1);
+    assert_eq!(ldap.searches().len(), 1);
+
+    // Values keep their case, so this is another search.
+    let (_, code) = client
+        .search(4, search("ou=people,o=example", &["cn", "mail"]))
+        .await;
+    assert_eq!(code, LdapResultCode::Success);
+    assert_eq!(ldap.searches().len(), 2);
+}
+
+#[tokio::test]
+async fn test_search_denied_attribute_filter() {
+    let ldap = FakeLdap::start().await;
+    let config = parse_config(
+        r#"
+["cn=app"]
+denied_attributes = ["userPassword"]
+denial_response = "insufficient_access_rights"
+"#,
+    );
+    let app_state = test_app_state(&config, ldap.upstreams());
+    let mut client = TestClient::bound(&app_state, "cn=app").await;
+
+    let search = |filter: &str| LdapSearchRequest {
+        filter: ldap3_proto::parse_ldap_filter_str(filter).expect("Invalid filter"),
+        ..test_search("o=example")
+    };
+
+    let (_, code) = client.search(2, search("(uid=alice)")).await;
+    assert_eq!(code, LdapResultCode::Success);
+
+    // Whether entries match a denied attribute would reveal its value.
+    let (entries, code) = client
+        .search(3, search("(&(uid=alice)(userPassword={SSHA}a*))"))
+        .await;
+    assert_eq!(code, LdapResultCode::InsufficentAccessRights);
+    assert!(entries.is_empty());
+    assert_eq!(ldap.searches().len(), 1);
+}
+
+#[tokio::test]
+async fn test_cache_identity() {
+    let ldap = FakeLdap::start().await;
+    let config = parse_config(
+        r#"
+[group_policy]
+search = { base = "ou=groups,o=example", filter = "(member=${dn})" }
+groups = [["*,ou=groups,o=example", "readers"]]
+
+[policies.readers]
+
+["cn=app1"]
+map_to_dn = "cn=app"
+map_to_secret = "secret"
+["cn=app2"]
+map_to_dn = "cn=app"
+map_to_secret = "secret"
+
+["cn=member1"]
+cache_group = "members"
+["cn=member2"]
+cache_group = "members"
+"#,
+    );
+    let app_state = test_app_state(&config, ldap.upstreams());
+
+    // The results of a search as the dn, and how many times it was sent to the ldap
+    // server in all.
+    let search_as = |dn: &'static str| {
+        let app_state = app_state.clone();
+        let ldap = &ldap;
+        async move {
+            let mut client = TestClient::bound(&app_state, dn).await;
+            let (entries, code) = client.search(2, test_search("o=example")).await;
+            assert_eq!(code, LdapResultCode::Success);
+            let bound_as = entries[0].attributes[0].vals[0].clone();
+            let searches = ldap
+                .searches()
+                .iter()
+                .filter(|sr| sr.base == "o=example")
+                .count();
+            (
+                String::from_utf8(bound_as).expect("Invalid value"),
+                searches,
+            )
+        }
+    };
+
+    // The dns of a group policy share the policy, but not their results.
+    assert_eq!(
+        search_as("uid=alice,o=example").await,
+        ("uid=alice,o=example".to_string(), 1)
+    );
+    assert_eq!(
+        search_as("uid=bob,o=example").await,
+        ("uid=bob,o=example".to_string(), 2)
+    );
+    assert_eq!(
+        search_as("uid=alice,o=example").await,
+        ("uid=alice,o=example".to_string(), 2)
+    );
+
+    // The dns that are mapped to the same dn share results.
+    assert_eq!(search_as("cn=app1").await, ("cn=app".to_string(), 3));
+    assert_eq!(search_as("cn=app2").await, ("cn=app".to_string(), 3));
+
+    // So do the dns in a cache group.
+    assert_eq!(search_as("cn=member1").await, ("cn=member1".to_string(), 4));
+    assert_eq!(search_as("cn=member2").await, ("cn=member1".to_string(), 4));
+}
+
+#[tokio::test]
+async fn test_write_invalidates_cache() {
+    let ldap = FakeLdap::start().await;
+    let config = parse_config(
+        r#"
+["cn=writer"]
+write_policy = { operations = ["modify"], subtree = "o=example", attributes = ["mail"] }
+"#,
+    );
+    let app_state = test_app_state(&config, ldap.upstreams());
+    let mut client = TestClient::bound(&app_state, "cn=writer").await;
+
+    let (_, code) = client.search(2, test_search("ou=people,o=example")).await;
+    assert_eq!(code, LdapResultCode::Success);
+    let (_, code) = client.search(3, test_search("ou=groups,o=example")).await;
+    assert_eq!(code, LdapResultCode::Success);
+    let (_, code) = client.search(4, test_search("ou=people,o=example")).await;
+    assert_eq!(code, LdapResultCode::Success);
+    assert_eq!(ldap.searches().len(), 2);
+
+    client
+        .send(
+            5,
+            LdapOp::ModifyRequest(LdapModifyRequest {
+                dn: "uid=alice,ou=people,o=example".to_string(),
+                changes: vec![LdapModify {
+                    operation: LdapModifyType::Replace,
+                    modification: LdapPartialAttribute {
+                        atype: "mail".to_string(),
+                        vals: vec![b"alice@example.com".to_vec()],
+                    },
+                }],
+            }),
+        )
+        .await;
+    match client.recv().await.op {
+        LdapOp::ModifyResponse(res) => assert_eq!(res.code, LdapResultCode::Success),
+        op => panic!("Unexpected response {:?}", op),
+    }
+
+    // Only the results that the write could have changed are searched for again.
+    let (_, code) = client.search(6, test_search("ou=people,o=example")).await;
+    assert_eq!(code, LdapResultCode::Success);
+    let (_, code) = client.search(7, test_search("ou=groups,o=example")).await;
+    assert_eq!(code, LdapResultCode::Success);
+    assert_eq!(ldap.searches().len(), 3);
+    let (_, code) = client.search(8, test_search("ou=people,o=example")).await;
+    assert_eq!(code, LdapResultCode::Success);
+    assert_eq!(ldap.searches().len(), 3);
+}
+
+#[tokio::test]
+async fn test_cache_snapshot_values() {
+    let ldap = FakeLdap::start().await;
+    let config = parse_config("allow_all_bind_dns = true\ncache_entry_timeout = 1\n");
+    let app_state = test_app_state(&config, ldap.upstreams());
+    let mut client = TestClient::bound(&app_state, "cn=user,o=example").await;
+    let (_, code) = client.search(2, test_search("o=example")).await;
+    assert_eq!(code, LdapResultCode::Success);
+
+    let path =
+        std::env::temp_dir().join(format!("ldap-proxy-snapshot-values-{}", std::process::id()));
+    assert_eq!(
+        save_cache(&app_state.cache, &app_state.write_log, &path).expect("Failed to save cache"),
+        1
+    );
+
+    // Only the owner may read the snapshot, and values are stored compactly.
+    let metadata = std::fs::metadata(&path).expect("Failed to read snapshot metadata");
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+    let snapshot = std::fs::read_to_string(&path).expect("Failed to read snapshot");
+    // The entry's boundAs value, which is "cn=user,o=example".
+    assert!(snapshot.contains("\"Y249dXNlcixvPWV4YW1wbGU=\""));
+
+    // The loaded result is used instead of searching again.
+    let app_state = test_app_state(&config, ldap.upstreams());
+    assert_eq!(
+        load_cache(&app_state.cache, &path).expect("Failed to load cache"),
+        1
+    );
+    let mut client = TestClient::bound(&app_state, "cn=user,o=example").await;
+    let (entries, code) = client.search(2, test_search("o=example")).await;
+    assert_eq!(code, LdapResultCode::Success);
+    assert_eq!(entries.len(), 1);
+    assert_eq!(ldap.searches().len(), 1);
+
+    // Values that expire before the snapshot is loaded are dropped.
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    let app_state = test_app_state(&config, ldap.upstreams());
+    assert_eq!(
+        load_cache(&app_state.cache, &path).expect("Failed to load cache"),
+        0
+    );
+
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn test_cache_prewarm() {
+    let ldap = FakeLdap::start().await;
+    let config = parse_config(
+        r#"
+cache_entry_timeout = 2
+
+[[cache_prewarm]]
+bind_dn = "cn=app"
+bind_secret = "secret"
+base = "o=example"
+scope = "subtree"
+filter = "(objectClass=*)"
+
+["cn=app"]
+allowed_networks = ["10.0.0.0/8"]
+cache_group = "apps"
+["cn=reader"]
+cache_group = "apps"
+"#,
+    );
+    let app_state = test_app_state(&config, ldap.upstreams());
+    let query = config.cache_prewarm[0].clone();
+
+    // The proxy's own searches aren't limited to the allowed networks.
+    let started = Instant::now();
+    let valid_until = refresh_search(&app_state, &query)
+        .await
+        .expect("The prewarmed result wasn't cached");
+    assert!(valid_until > started + Duration::from_secs(1));
+    assert_eq!(ldap.searches().len(), 1);
+
+    // Clients that share the cache find the prewarmed result.
+    let mut client = TestClient::bound(&app_state, "cn=reader").await;
+    let (entries, code) = client.search(2, test_search("o=example")).await;
+    assert_eq!(code, LdapResultCode::Success);
+    assert_eq!(entries.len(), 1);
+    assert_eq!(ldap.searches().len(), 1);
+
+    // The result is searched for again a second before it expires, until the app state
+    // is dropped.
+    tokio::spawn(prewarm_task(
+        Arc::downgrade(&app_state),
+        query,
+        Duration::from_secs(1),
+    ));
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert_eq!(ldap.searches().len(), 3);
+    let (_, code) = client.search(3, test_search("o=example")).await;
+    assert_eq!(code, LdapResultCode::Success);
+    assert_eq!(ldap.searches().len(), 3);
+
+    drop(client);
+    drop(app_state);
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert_eq!(ldap.searches().len(), 3);
+}
+
+#[tokio::test]
+async fn test_cache_prewarm_empty() {
+    let ldap = FakeLdap::start().await;
+    let config = parse_config(
+        r#"
+cache_negative_timeout = 4
+
+[[cache_prewarm]]
+bind_dn = "cn=app"
+bind_secret = "secret"
+base = "ou=empty,o=example"
+scope = "subtree"
+filter = "(objectClass=*)"
+
+["cn=app"]
+"#,
+    );
+    let app_state = test_app_state(&config, ldap.upstreams());
+    let query = config.cache_prewarm[0].clone();
+
+    // The empty result is cached for less than the refresh ahead time, so it is searched
+    // for again halfway through that time rather than continuously.
+    tokio::spawn(prewarm_task(
+        Arc::downgrade(&app_state),
+        query,
+        Duration::from_secs(60),
+    ));
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert_eq!(ldap.searches().len(), 1);
+    tokio::time::sleep(Duration::from_millis(1000)).await;
+    assert_eq!(ldap.searches().len(), 2);
+}
+
+#[tokio::test]
+async fn test_cache_stale_if_error() {
+    let ldap = FakeLdap::start().await;
+    let config = parse_config(
+        r#"
+allow_all_bind_dns = true
+cache_entry_timeout = 1
+cache_stale_if_error = 60
+
+["cn=app"]
+map_to_dn = "cn=mapped,o=example"
+map_to_secret = "secret"
+"#,
+    );
+    let app_state = test_app_state(&config, ldap.upstreams());
+    let mut client = TestClient::bound(&app_state, "cn=user,o=example").await;
+    let (_, code) = client.search(2, test_search("o=example")).await;
+    assert_eq!(code, LdapResultCode::Success);
+    let mut app_client = TestClient::bound(&app_state, "cn=app").await;
+    let (_, code) = app_client.search(2, test_search("o=example")).await;
+    assert_eq!(code, LdapResultCode::Success);
+
+    // Once the result has expired and the ldap server is gone, bound clients are still
+    // sent the result.
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    ldap.stop();
+    let (entries, code) = client.search(3, test_search("o=example")).await;
+    assert_eq!(code, LdapResultCode::Success);
+    assert_eq!(entries.len(), 1);
+    assert_eq!(app_state.cache_stats.counts().stale_if_error, 1);
+
+    // Results that were never cached can't be sent, and the search fails.
+    let (entries, code) = client.search(4, test_search("ou=people,o=example")).await;
+    assert_eq!(code, LdapResultCode::OperationsError);
+    assert!(entries.is_empty());
+
+    // Binds are checked by the ldap server, so clients can't bind while it's gone.
+    let mut client = TestClient::connect(&app_state);
+    assert_eq!(
+        client.bind(1, "cn=user,o=example", "secret").await,
+        LdapResultCode::OperationsError
+    );
+
+    // Unless the ldap server doesn't check them, as the dn is mapped with a secret. Such
+    // clients may bind only to be sent the results that are cached.
+    let mut app_client = TestClient::bound(&app_state, "cn=app").await;
+    let (entries, code) = app_client.search(2, test_search("o=example")).await;
+    assert_eq!(code, LdapResultCode::Success);
+    assert_eq!(entries.len(), 1);
+    assert_eq!(app_state.cache_stats.counts().stale_if_error, 2);
+    let (entries, code) = app_client
+        .search(3, test_search("ou=people,o=example"))
+        .await;
+    assert_eq!(code, LdapResultCode::OperationsError);
+    assert!(entries.is_empty());
+}
+
+#[tokio::test]
+async fn test_cache_max_entry_bytes() {
+    let ldap = FakeLdap::start().await;
+    let config = parse_config("allow_all_bind_dns = true\n");
+    let app_state = test_app_state(&config, ldap.upstreams());
+
+    let mut client = TestClient::bound(&app_state, "cn=user,o=example").await;
+    for msgid in 2..4 {
+        let (entries, code) = client.search(msgid, test_search("o=example")).await;
+        assert_eq!(code, LdapResultCode::Success);
+        assert_eq!(entries.len(), 1);
+    }
+    assert_eq!(ldap.searches().len(), 1);
+
+    // Results that are too large are still sent, but aren't cached.
+    let ldap = FakeLdap::start().await;
+    let config = parse_config("allow_all_bind_dns = true\ncache_max_entry_bytes = 1\n");
+    let app_state = test_app_state(&config, ldap.upstreams());
+
+    let mut client = TestClient::bound(&app_state, "cn=user,o=example").await;
+    for msgid in 2..4 {
+        let (entries, code) = client.search(msgid, test_search("o=example")).await;
+        assert_eq!(code, LdapResultCode::Success);
+        assert_eq!(entries.len(), 1);
+    }
+    assert_eq!(ldap.searches().len(), 2);
+}
+
+fn test_result(code: LdapResultCode) -> LdapResult {
+    LdapResult {
+        code,
+        matcheddn: "".to_string(),
+        message: "".to_string(),
+        referral: vec![],
+    }
 }